@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::expression::*;
+
+// The value of a free variable, which is either a number or a list of numbers.
+// (With a list, the graph is drawn once for each number.)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum VariableValue {
+    Number(f64),
+    List(Vec<f64>),
+}
+
+// Every combination gets graphed separately, so this keeps a few long lists
+// from asking for more graphs than could ever be drawn
+pub const MAX_LIST_COMBINATIONS: usize = 1000;
+
+// Expands list-valued variables (and list literals) used by the expressions into
+// one set of plain variable values per combination of list elements. The
+// combinations are ordered like nested loops over the lists sorted by name, and
+// a result's position in the returned vector is its list index.
+pub fn broadcast_var_values(
+    expressions: &[&dyn Expression],
+    var_values: &HashMap<String, VariableValue>,
+) -> Result<Vec<HashMap<String, f64>>, String> {
+    let mut used_variables = std::collections::HashSet::new();
+    let mut lists: Vec<(String, Vec<f64>)> = vec![];
+    for expression in expressions {
+        used_variables.extend(expression.get_variables());
+        for (index_variable, length) in expression.get_list_lengths() {
+            if !lists.iter().any(|(name, _)| name == &index_variable) {
+                lists.push((index_variable, (0..length).map(|i| i as f64).collect()));
+            }
+        }
+    }
+
+    let mut values = HashMap::new();
+    for (name, value) in var_values {
+        match value {
+            VariableValue::Number(value) => {
+                values.insert(name.clone(), *value);
+            }
+            VariableValue::List(items) => {
+                if used_variables.contains(name) {
+                    lists.push((name.clone(), items.clone()));
+                }
+            }
+        }
+    }
+    lists.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let combination_count = lists
+        .iter()
+        .fold(1_usize, |acc, (_, items)| acc.saturating_mul(items.len()));
+    if combination_count > MAX_LIST_COMBINATIONS {
+        return Err(format!(
+            "Lists have too many combinations to graph ({} is the most)",
+            MAX_LIST_COMBINATIONS
+        ));
+    }
+
+    let mut combinations = vec![values];
+    for (name, items) in lists {
+        combinations = combinations
+            .iter()
+            .flat_map(|values| {
+                items.iter().map(|item| {
+                    let mut values = values.clone();
+                    values.insert(name.clone(), *item);
+                    values
+                })
+            })
+            .collect();
+    }
+
+    Ok(combinations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphing::*;
+    use crate::{
+        mathjson_value_to_equation, mathjson_value_to_expression, polylines_to_float_array,
+    };
+
+    fn parse(math_json: &str) -> Box<dyn Expression> {
+        mathjson_value_to_expression(&serde_json::from_str(math_json).unwrap()).unwrap()
+    }
+
+    fn lists(lists: &[(&str, &[f64])]) -> HashMap<String, VariableValue> {
+        lists
+            .iter()
+            .map(|(name, items)| (name.to_string(), VariableValue::List(items.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn combinations_are_ordered_like_nested_loops_by_name() {
+        let expression = parse(r#"["Add","b","a"]"#);
+        let combinations = broadcast_var_values(
+            &[&*expression],
+            &lists(&[("b", &[3.0, 4.0]), ("a", &[1.0, 2.0])]),
+        )
+        .unwrap();
+        let pairs: Vec<_> = combinations
+            .iter()
+            .map(|values| (values["a"], values["b"]))
+            .collect();
+        assert_eq!(pairs, [(1.0, 3.0), (1.0, 4.0), (2.0, 3.0), (2.0, 4.0)]);
+    }
+
+    #[test]
+    fn list_literals_get_an_index_variable() {
+        let expression = parse(r#"["Add","x",["List",1,2,3]]"#);
+        let combinations = broadcast_var_values(&[&*expression], &HashMap::new()).unwrap();
+        assert_eq!(combinations.len(), 3);
+        for (i, values) in combinations.iter().enumerate() {
+            let mut values = values.clone();
+            values.insert("x".to_string(), 0.0);
+            assert_eq!(expression.evaluate(&values), Ok(i as f64 + 1.0));
+        }
+    }
+
+    #[test]
+    fn unused_lists_are_not_broadcast() {
+        let expression = parse(r#"["Add","x","a"]"#);
+        let mut var_values = lists(&[("c", &[1.0, 2.0, 3.0])]);
+        var_values.insert("a".to_string(), VariableValue::Number(5.0));
+        let combinations = broadcast_var_values(&[&*expression], &var_values).unwrap();
+        assert_eq!(combinations.len(), 1);
+        assert_eq!(combinations[0].get("a"), Some(&5.0));
+        assert!(!combinations[0].contains_key("c"));
+    }
+
+    #[test]
+    fn too_many_combinations_are_an_error() {
+        let expression = parse(r#"["Add","a","b"]"#);
+        let items: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        let var_values = lists(&[("a", &items), ("b", &items)]);
+        assert!(broadcast_var_values(&[&*expression], &var_values).is_err());
+    }
+
+    #[test]
+    fn contours_are_tagged_with_their_list_index() {
+        let equation =
+            mathjson_value_to_equation(&serde_json::from_str(r#"["Equal","y","a"]"#).unwrap())
+                .unwrap();
+        let window = GraphBox::new(-3.0, 3.0, -3.0, 3.0);
+        let graphs = broadcast_var_values(
+            &[&*equation.left, &*equation.right],
+            &lists(&[("a", &[-1.0, 2.0])]),
+        )
+        .unwrap()
+        .iter()
+        .map(|var_values| {
+            graph_equation_2d(
+                "x",
+                "y",
+                &window,
+                &equation,
+                7,
+                3,
+                None,
+                &EdgeRefinement::default(),
+                var_values,
+            )
+            .unwrap()
+        })
+        .collect();
+
+        // Each contour ends with [Infinity, list index, Infinity, closed]
+        let float_array = polylines_to_float_array(graphs);
+        let mut list_indices = vec![];
+        let mut ys = vec![];
+        let mut i = 0;
+        while i < float_array.len() {
+            if float_array[i] == f64::INFINITY {
+                let list_index = float_array[i + 1] as usize;
+                let a = [-1.0, 2.0][list_index];
+                assert!(ys.iter().all(|y: &f64| (y - a).abs() < 1e-6));
+                list_indices.push(list_index);
+                ys.clear();
+                i += 4;
+            } else {
+                ys.push(float_array[i + 1]);
+                i += 2;
+            }
+        }
+        assert_eq!(list_indices.first(), Some(&0));
+        assert_eq!(list_indices.last(), Some(&1));
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use wasm_bindgen::prelude::*;
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date)]
+    pub(crate) fn now() -> f64;
+}
+
+// Milliseconds since some fixed time, for keeping to time budgets
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64() * 1000.0)
+}
+
+// Asking the clock is slower than everything else, so only do it every so often
+const CLOCK_CHECK_INTERVAL: usize = 64;
+
+// Loops that do a lot of work between checks (like a whole row of a grid) ask
+// the clock once this many evaluations have been spent since it was last asked,
+// however few checks that's been
+const CLOCK_CHECK_EVALUATIONS: usize = 256;
+
+// A JavaScript function to use as a budget's clock. Only for wasm built without
+// threads, since a JavaScript function can't be called from another thread.
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+struct JsClock(js_sys::Function);
+
+// SAFETY: without the atomics feature, wasm can't start any other threads, so
+// the function only ever gets used from the thread it was made on
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+unsafe impl Send for JsClock {}
+
+// SAFETY: same as Send, there's no other thread to share it with
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+unsafe impl Sync for JsClock {}
+
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+impl JsClock {
+    // A clock that throws or doesn't return a number never runs out
+    fn now(&self) -> f64 {
+        self.0
+            .call0(&JsValue::NULL)
+            .ok()
+            .and_then(|time| time.as_f64())
+            .unwrap_or(f64::NAN)
+    }
+}
+
+// Lets one thread tell a graphing call on another to stop early
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+#[wasm_bindgen]
+impl CancellationToken {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+struct BudgetState {
+    max_evaluations: Option<usize>,
+    // In the clock's units (milliseconds for the default clock)
+    max_time: Option<f64>,
+    clock: Box<dyn Fn() -> f64 + Send + Sync>,
+    start: Mutex<f64>,
+    token: Mutex<CancellationToken>,
+    evaluations: AtomicUsize,
+    checks: AtomicUsize,
+    // How many evaluations had been spent when the clock was last asked
+    clock_evaluations: AtomicUsize,
+    truncated: AtomicBool,
+}
+
+// Limits on how much work a graphing call can do. Once the evaluations or the
+// time run out, or the call gets cancelled, the graphers stop splitting cells
+// and sampling points, and return what they have so far. is_truncated says
+// whether that happened. Copies share the same counts, so JavaScript can keep
+// one to check after handing it over.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct EvaluationBudget {
+    state: Arc<BudgetState>,
+}
+
+#[wasm_bindgen]
+impl EvaluationBudget {
+    // max_time is in milliseconds, from when the budget is made (or restarted)
+    #[wasm_bindgen(constructor)]
+    pub fn new(max_evaluations: Option<usize>, max_time: Option<f64>) -> Self {
+        EvaluationBudget::with_clock(max_evaluations, max_time, Box::new(now))
+    }
+
+    // Stops at the next check, from this thread or any other
+    pub fn cancel(&self) {
+        self.state.token.lock().unwrap().cancel();
+    }
+
+    // Uses a token that can be shared with other budgets
+    pub fn set_cancellation_token(&self, token: &CancellationToken) {
+        *self.state.token.lock().unwrap() = token.clone();
+    }
+
+    // Whether a graph stopped early because the budget ran out
+    pub fn is_truncated(&self) -> bool {
+        self.state.truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn get_evaluations(&self) -> usize {
+        self.state.evaluations.load(Ordering::Relaxed)
+    }
+
+    // Starts counting evaluations and time again, so the budget can be used for
+    // another call. Cancelling can't be undone.
+    pub fn restart(&self) {
+        *self.state.start.lock().unwrap() = (self.state.clock)();
+        self.state.evaluations.store(0, Ordering::Relaxed);
+        self.state.checks.store(0, Ordering::Relaxed);
+        self.state.clock_evaluations.store(0, Ordering::Relaxed);
+        self.state.truncated.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+#[wasm_bindgen]
+impl EvaluationBudget {
+    // Measures max_time with a JavaScript function (like performance.now),
+    // in whatever units it returns
+    pub fn with_js_clock(
+        max_evaluations: Option<usize>,
+        max_time: Option<f64>,
+        clock: js_sys::Function,
+    ) -> Self {
+        let clock = JsClock(clock);
+        EvaluationBudget::with_clock(max_evaluations, max_time, Box::new(move || clock.now()))
+    }
+}
+
+impl EvaluationBudget {
+    pub fn unlimited() -> Self {
+        EvaluationBudget::with_clock(None, None, Box::new(|| 0.0))
+    }
+
+    // Measures max_time with the given clock, in whatever units it uses
+    pub fn with_clock(
+        max_evaluations: Option<usize>,
+        max_time: Option<f64>,
+        clock: Box<dyn Fn() -> f64 + Send + Sync>,
+    ) -> Self {
+        let start = clock();
+        EvaluationBudget {
+            state: Arc::new(BudgetState {
+                max_evaluations,
+                max_time,
+                clock,
+                start: Mutex::new(start),
+                token: Mutex::new(CancellationToken::new()),
+                evaluations: AtomicUsize::new(0),
+                checks: AtomicUsize::new(0),
+                clock_evaluations: AtomicUsize::new(0),
+                truncated: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    // Counts evaluations against the budget
+    pub(crate) fn spend(&self, evaluations: usize) {
+        self.state
+            .evaluations
+            .fetch_add(evaluations, Ordering::Relaxed);
+    }
+
+    // Whether to stop, which marks the graph as truncated
+    pub(crate) fn is_exhausted(&self) -> bool {
+        let state = &self.state;
+        if state.truncated.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let evaluations = state.evaluations.load(Ordering::Relaxed);
+        let out_of_evaluations = state.max_evaluations.is_some_and(|max| evaluations >= max);
+        let out_of_time = match state.max_time {
+            Some(max_time) => {
+                let checks = state.checks.fetch_add(1, Ordering::Relaxed);
+                let since_clock =
+                    evaluations.saturating_sub(state.clock_evaluations.load(Ordering::Relaxed));
+                if checks.is_multiple_of(CLOCK_CHECK_INTERVAL)
+                    || since_clock >= CLOCK_CHECK_EVALUATIONS
+                {
+                    state
+                        .clock_evaluations
+                        .store(evaluations, Ordering::Relaxed);
+                    (state.clock)() - *state.start.lock().unwrap() >= max_time
+                } else {
+                    false
+                }
+            }
+            None => false,
+        };
+        if out_of_evaluations || out_of_time || state.token.lock().unwrap().is_cancelled() {
+            state.truncated.store(true, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A clock that only moves when the test says so
+    fn manual_clock() -> (Arc<Mutex<f64>>, Box<dyn Fn() -> f64 + Send + Sync>) {
+        let time = Arc::new(Mutex::new(0.0));
+        let clock_time = time.clone();
+        (time, Box::new(move || *clock_time.lock().unwrap()))
+    }
+
+    #[test]
+    fn coarse_loops_still_run_out_of_time() {
+        let (time, clock) = manual_clock();
+        let budget = EvaluationBudget::with_clock(None, Some(10.0), clock);
+        assert!(!budget.is_exhausted());
+
+        // One check per row of a big grid, far fewer than the check interval
+        *time.lock().unwrap() = 20.0;
+        budget.spend(CLOCK_CHECK_EVALUATIONS);
+        assert!(budget.is_exhausted());
+        assert!(budget.is_truncated());
+    }
+
+    #[test]
+    fn fine_loops_ask_the_clock_every_so_often() {
+        let (time, clock) = manual_clock();
+        let budget = EvaluationBudget::with_clock(None, Some(10.0), clock);
+        assert!(!budget.is_exhausted());
+
+        *time.lock().unwrap() = 20.0;
+        let checks = (1..=CLOCK_CHECK_INTERVAL)
+            .take_while(|_| {
+                budget.spend(1);
+                !budget.is_exhausted()
+            })
+            .count();
+        assert_eq!(checks, CLOCK_CHECK_INTERVAL - 1);
+    }
+
+    #[test]
+    fn restarted_budgets_ask_the_clock_straight_away() {
+        let (time, clock) = manual_clock();
+        let budget = EvaluationBudget::with_clock(None, Some(10.0), clock);
+        for _ in 0..5 {
+            assert!(!budget.is_exhausted());
+        }
+
+        budget.restart();
+        *time.lock().unwrap() = 20.0;
+        assert!(budget.is_exhausted());
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use crate::budget::*;
+use crate::expression::*;
+use crate::graphing::*;
+use crate::point::*;
+use crate::sampling::*;
+use crate::segment::*;
+use crate::vector::*;
+
+// The window is covered by a block of 2^BLOCK_LEVELS by 2^BLOCK_LEVELS cells
+const BLOCK_LEVELS: u32 = 3;
+
+// Forget the remembered values past this many, so memory doesn't grow forever
+const MAX_CACHED_VALUES: usize = 1_000_000;
+
+// The samples are keyed on a grid made for one level, which is still fine
+// enough (and big enough) this many levels up or down
+const MAX_SAMPLE_LEVEL_CHANGE: i32 = 8;
+
+// The trees for one expression, for square cells 2^level wide whose bottom
+// left corners are at (x, y) * 2^level. The trees depend on the variable
+// values, so the cache should be made again when they change.
+pub struct TreeCache {
+    var1: String,
+    var2: String,
+    expression: Box<dyn Expression>,
+    // The simplified partial derivatives of the expression with respect to
+    // var1 and var2
+    derivatives: (Box<dyn Expression>, Box<dyn Expression>),
+    // Keyed by level, x, y, depth and search depth. The trees haven't been
+    // stitched yet, since that depends on their neighbours.
+    trees: HashMap<(i32, i64, i64, i64, i64), QuadTreeNode>,
+    samples: SampleCache,
+    gradients: SampleCache<Vec2D>,
+    // The level the samples' grid was made for
+    sample_level: Option<i32>,
+}
+
+impl TreeCache {
+    pub fn new(
+        var1: &str,
+        var2: &str,
+        expression: Box<dyn Expression>,
+        derivatives: (Box<dyn Expression>, Box<dyn Expression>),
+    ) -> Self {
+        TreeCache {
+            var1: var1.to_string(),
+            var2: var2.to_string(),
+            expression,
+            derivatives,
+            trees: HashMap::new(),
+            samples: SampleCache::new(Point2D(0.0, 0.0), 1.0),
+            gradients: SampleCache::new(Point2D(0.0, 0.0), 1.0),
+            sample_level: None,
+        }
+    }
+
+    pub fn get_axes(&self) -> (&str, &str) {
+        (&self.var1, &self.var2)
+    }
+
+    // How many values have been asked for and evaluated since the cache was made
+    pub fn get_sampling_stats(&self) -> SamplingStats {
+        self.samples.get_stats()
+    }
+
+    // Graphs expression = 0 in the cells that overlap the window, where the
+    // cells are about a quarter of the window's size. Trees that get cut short
+    // by the budget aren't kept.
+    pub fn graph(
+        &mut self,
+        window: &GraphBox,
+        depth: i64,
+        search_depth: i64,
+        refinement: &EdgeRefinement,
+        var_values: &HashMap<String, f64>,
+        budget: &EvaluationBudget,
+    ) -> Result<Vec<Polyline2D>, String> {
+        let TreeCache {
+            var1,
+            var2,
+            expression,
+            derivatives: (dx, dy),
+            trees,
+            samples,
+            gradients,
+            sample_level,
+        } = self;
+        let expression = &**expression;
+        check_variables_2d(expression, var1, var2, var_values)?;
+
+        let size = (window.x_max - window.x_min).max(window.y_max - window.y_min);
+        let level = size.log2().ceil() as i32 - 2;
+        let cell_size = 2.0_f64.powi(level);
+        if size.is_nan() || size <= 0.0 || !cell_size.is_finite() || cell_size == 0.0 {
+            return Err("Invalid window".to_string());
+        }
+
+        // Keep the leaves about as big as they'd be for the window itself
+        let extra_depth = if 4.0 * cell_size / size > std::f64::consts::SQRT_2 {
+            1
+        } else {
+            0
+        };
+        let depth = (depth + extra_depth - 2).max(0);
+        let search_depth = (search_depth + extra_depth - 2).max(0);
+
+        let regrid = match sample_level {
+            Some(sample_level) => (level - *sample_level).abs() > MAX_SAMPLE_LEVEL_CHANGE,
+            None => true,
+        };
+        if regrid {
+            let quantum = cell_size * 2.0_f64.powi(-QUANTIZATION_BITS);
+            samples.reset(Point2D(0.0, 0.0), quantum);
+            gradients.reset(Point2D(0.0, 0.0), quantum);
+            *sample_level = Some(level);
+        } else if samples.len() > MAX_CACHED_VALUES {
+            samples.clear();
+            gradients.clear();
+        }
+
+        let context = GraphContext::new(var1, var2, var_values, budget);
+        let evaluate = get_evaluator_2d(expression, var1, var2, var_values);
+        let evaluate = |p: Point2D| {
+            budget.spend(1);
+            evaluate(p)
+        };
+        let f = |p: Point2D| samples.get_or_evaluate(p, &evaluate);
+
+        let evaluate_gradient = get_gradient_evaluator_from_derivatives_2d(
+            dx.clone(),
+            dy.clone(),
+            var1,
+            var2,
+            var_values,
+        );
+        let evaluate_gradient = get_cheating_gradient(&evaluate_gradient);
+        let df = |p: Point2D| gradients.get_or_evaluate(p, &evaluate_gradient);
+
+        let exclusions = expression.get_real_domain().get_exclusions();
+        let exclusions: Vec<_> = exclusions
+            .iter()
+            .map(|exclusion| get_evaluator_2d(&**exclusion, var1, var2, var_values))
+            .collect();
+        let exclusions: Vec<&dyn Fn(Point2D) -> f64> = exclusions
+            .iter()
+            .map(|exclusion| exclusion as &dyn Fn(Point2D) -> f64)
+            .collect();
+
+        let x = (window.x_min / cell_size).floor() as i64;
+        let y = (window.y_min / cell_size).floor() as i64;
+        let block_cells = 1 << BLOCK_LEVELS;
+        let block = GraphBox::new(
+            x as f64 * cell_size,
+            (x + block_cells) as f64 * cell_size,
+            y as f64 * cell_size,
+            (y + block_cells) as f64 * cell_size,
+        );
+
+        let mut new_keys = vec![];
+        let mut sampling_error = None;
+        let mut get_cell = |cell_x: i64, cell_y: i64| {
+            let area = GraphBox::new(
+                cell_x as f64 * cell_size,
+                (cell_x + 1) as f64 * cell_size,
+                cell_y as f64 * cell_size,
+                (cell_y + 1) as f64 * cell_size,
+            );
+            // Cells outside the window get left out of the graph entirely
+            if area.x_min >= window.x_max || area.y_min >= window.y_max {
+                return QuadTreeNode::Zero;
+            }
+
+            let key = (level, cell_x, cell_y, depth, search_depth);
+            if !trees.contains_key(&key) {
+                new_keys.push(key);
+            }
+            let tree = match trees.get(&key) {
+                Some(tree) => tree.clone(),
+                None => match get_zoomed_in_tree(trees, key, &area, &f, &df, budget) {
+                    Some(tree) => tree,
+                    None => match sample_tree_corners_2d(
+                        expression,
+                        &context,
+                        &area,
+                        search_depth,
+                        samples,
+                    ) {
+                        Ok(()) => build_tree(depth, search_depth, &area, &f, &df, budget),
+                        Err(error) => {
+                            sampling_error.get_or_insert(error);
+                            return QuadTreeNode::Zero;
+                        }
+                    },
+                },
+            };
+            trees.insert(key, tree.clone());
+            tree
+        };
+
+        let mut tree = assemble_block(BLOCK_LEVELS, x, y, &mut get_cell);
+        if let Some(error) = sampling_error {
+            for key in new_keys {
+                trees.remove(&key);
+            }
+            return Err(error);
+        }
+        stitch_tree(&mut tree, &block, &f, &df, &exclusions, refinement);
+
+        if budget.is_truncated() {
+            for key in new_keys {
+                trees.remove(&key);
+            }
+        }
+
+        // Only keep trees we're likely to need for the next pan or zoom
+        let center = (
+            (window.x_min + window.x_max) / 2.0,
+            (window.y_min + window.y_max) / 2.0,
+        );
+        trees.retain(|&(tree_level, tree_x, tree_y, _, _), _| {
+            let tree_size = 2.0_f64.powi(tree_level);
+            let reach = 2.0 * block_cells as f64 * cell_size;
+            (tree_level - level).abs() <= 1
+                && ((tree_x as f64 + 0.5) * tree_size - center.0).abs() <= reach
+                && ((tree_y as f64 + 0.5) * tree_size - center.1).abs() <= reach
+        });
+
+        Ok(get_contours_2d(&tree, &df))
+    }
+}
+
+// Puts the cells from (x, y) to (x + 2^levels, y + 2^levels) together into one
+// tree, in the same order as GraphBox::get_quadrant
+fn assemble_block(
+    levels: u32,
+    x: i64,
+    y: i64,
+    get_cell: &mut dyn FnMut(i64, i64) -> QuadTreeNode,
+) -> QuadTreeNode {
+    if levels == 0 {
+        return get_cell(x, y);
+    }
+
+    let half = 1 << (levels - 1);
+    let children = [
+        assemble_block(levels - 1, x, y, get_cell),
+        assemble_block(levels - 1, x + half, y, get_cell),
+        assemble_block(levels - 1, x, y + half, get_cell),
+        assemble_block(levels - 1, x + half, y + half, get_cell),
+    ];
+    QuadTreeNode::Root(Box::new(QuadTreeRootNode { children }))
+}
+
+// When zooming in, the cell is a quadrant of a cached cell one level up,
+// whose leaves only need splitting once more
+fn get_zoomed_in_tree(
+    trees: &HashMap<(i32, i64, i64, i64, i64), QuadTreeNode>,
+    (level, x, y, depth, search_depth): (i32, i64, i64, i64, i64),
+    area: &GraphBox,
+    f: &(impl Fn(Point2D) -> f64 + Sync),
+    df: &(impl Fn(Point2D) -> Vec2D + Sync),
+    budget: &EvaluationBudget,
+) -> Option<QuadTreeNode> {
+    fn split_leaves(
+        node: &mut QuadTreeNode,
+        area: &GraphBox,
+        f: &(impl Fn(Point2D) -> f64 + Sync),
+        df: &(impl Fn(Point2D) -> Vec2D + Sync),
+        budget: &EvaluationBudget,
+    ) {
+        match node {
+            QuadTreeNode::Root(root) => {
+                for (i, child) in root.children.iter_mut().enumerate() {
+                    split_leaves(child, &area.get_quadrant(i as u64), f, df, budget);
+                }
+            }
+            QuadTreeNode::Leaf(_) => *node = build_tree(1, 1, area, f, df, budget),
+            _ => {}
+        }
+    }
+
+    let parent_key = (
+        level + 1,
+        x.div_euclid(2),
+        y.div_euclid(2),
+        depth,
+        search_depth,
+    );
+    let quadrant = (x.rem_euclid(2) + 2 * y.rem_euclid(2)) as usize;
+    let mut tree = match trees.get(&parent_key)? {
+        QuadTreeNode::Root(root) => root.children[quadrant].clone(),
+        QuadTreeNode::Leaf(_) => return None,
+        boring => boring.clone(),
+    };
+
+    split_leaves(&mut tree, area, f, df, budget);
+    Some(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equation::*;
+    use crate::mathjson_value_to_equation;
+
+    fn parse(math_json: &str) -> Equation {
+        mathjson_value_to_equation(&serde_json::from_str(math_json).unwrap()).unwrap()
+    }
+
+    fn sorted_points(polylines: &[Polyline2D]) -> Vec<Point2D> {
+        let mut points: Vec<_> = polylines
+            .iter()
+            .flat_map(|polyline| polyline.points.iter().copied())
+            .collect();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        points.dedup();
+        points
+    }
+
+    #[test]
+    fn panning_only_evaluates_the_new_cells() {
+        let equation = parse(r#"["Equal",["Add",["Power","x",2],["Power","y",2]],1]"#);
+        let expression = Plus::new(vec![
+            equation.left.clone(),
+            Box::new(Minus::new(equation.right.clone())),
+        ])
+        .basic_simplify();
+        let derivatives = (
+            expression.derivative("x").basic_simplify(),
+            expression.derivative("y").basic_simplify(),
+        );
+        let mut cache = TreeCache::new("x", "y", expression, derivatives);
+        let var_values = HashMap::new();
+        let budget = EvaluationBudget::unlimited();
+        let refinement = EdgeRefinement::default();
+        let (depth, search_depth) = (7, 4);
+
+        // A 4 wide window has cells 1 wide, so this pans by one cell
+        let window = GraphBox::new(-2.0, 2.0, -2.0, 2.0);
+        cache
+            .graph(
+                &window,
+                depth,
+                search_depth,
+                &refinement,
+                &var_values,
+                &budget,
+            )
+            .unwrap();
+        let before = cache.get_sampling_stats().evaluations;
+        let window = GraphBox::new(-1.0, 3.0, -2.0, 2.0);
+        let cached = cache
+            .graph(
+                &window,
+                depth,
+                search_depth,
+                &refinement,
+                &var_values,
+                &budget,
+            )
+            .unwrap();
+        let evaluations = cache.get_sampling_stats().evaluations - before;
+
+        // The new column of 4 cells misses the circle, so each gets sampled on
+        // a 4 by 4 grid plus the centres of its 16 squares, and shares its left
+        // edge with the old column
+        let corners = 4 * (4 * 4 + 1);
+        let centers = 4 * 16;
+        assert_eq!(evaluations, corners + centers);
+
+        let uncached = graph_equation_2d(
+            "x",
+            "y",
+            &window,
+            &equation,
+            depth,
+            search_depth,
+            None,
+            &refinement,
+            &var_values,
+        )
+        .unwrap();
+        let (cached, uncached) = (sorted_points(&cached), sorted_points(&uncached));
+        assert_eq!(cached.len(), uncached.len());
+        for (a, b) in cached.iter().zip(&uncached) {
+            assert!((a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9);
+        }
+    }
+}
@@ -6,6 +6,11 @@ use std::collections::HashMap;
 pub trait Set: ASTNode + std::fmt::Debug {
     fn contains(&self, variables: &HashMap<String, f64>) -> bool;
     fn basic_simplify(&self) -> Box<dyn Set>;
+    // Expressions which must be nonzero for a point to be in this set. (For
+    // example, the real domain of 1/x excludes wherever x = 0.)
+    fn get_exclusions(&self) -> Vec<Box<dyn Expression>> {
+        vec![]
+    }
 
     fn clone_dyn(&self) -> Box<dyn Set>;
     fn as_any(&self) -> &dyn Any;
@@ -103,6 +108,12 @@ impl Set for Union {
             Box::new(Union { sets })
         }
     }
+    fn get_exclusions(&self) -> Vec<Box<dyn Expression>> {
+        self.sets
+            .iter()
+            .flat_map(|set| set.get_exclusions())
+            .collect()
+    }
     fn clone_dyn(&self) -> Box<dyn Set> {
         Box::new(self.clone())
     }
@@ -152,6 +163,12 @@ impl Set for Intersection {
             Box::new(Intersection { sets })
         }
     }
+    fn get_exclusions(&self) -> Vec<Box<dyn Expression>> {
+        self.sets
+            .iter()
+            .flat_map(|set| set.get_exclusions())
+            .collect()
+    }
     fn clone_dyn(&self) -> Box<dyn Set> {
         Box::new(self.clone())
     }
@@ -229,6 +246,15 @@ impl Set for Equation {
 
         self.clone_dyn()
     }
+    fn get_exclusions(&self) -> Vec<Box<dyn Expression>> {
+        match self.operator {
+            ComparisonOperator::NotEqual => vec![Box::new(Plus::new(vec![
+                self.left.clone(),
+                Box::new(Minus::new(self.right.clone())),
+            ]))],
+            _ => vec![],
+        }
+    }
     fn clone_dyn(&self) -> Box<dyn Set> {
         Box::new(self.clone())
     }
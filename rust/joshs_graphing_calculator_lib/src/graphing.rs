@@ -1,7 +1,10 @@
 use wasm_bindgen::prelude::*;
 
+use crate::budget::*;
 use crate::equation::*;
 use crate::expression::*;
+use crate::parallel::*;
+use crate::sampling::*;
 use crate::triangle::Triangle3D;
 use na::{OMatrix, U1, U2, U3};
 use serde::{Deserialize, Serialize};
@@ -20,7 +23,9 @@ pub struct GraphBox {
     pub y_max: f64,
 }
 
+#[wasm_bindgen]
 impl GraphBox {
+    #[wasm_bindgen(constructor)]
     pub fn new(x_min: f64, x_max: f64, y_min: f64, y_max: f64) -> Self {
         GraphBox {
             x_min,
@@ -29,7 +34,9 @@ impl GraphBox {
             y_max,
         }
     }
+}
 
+impl GraphBox {
     pub fn get_quadrant(&self, index: u64) -> GraphBox {
         let x_mid = (self.x_min + self.x_max) / 2.0;
         let y_mid = (self.y_min + self.y_max) / 2.0;
@@ -64,7 +71,9 @@ pub struct GraphBox3D {
     pub z_max: f64,
 }
 
+#[wasm_bindgen]
 impl GraphBox3D {
+    #[wasm_bindgen(constructor)]
     pub fn new(x_min: f64, x_max: f64, y_min: f64, y_max: f64, z_min: f64, z_max: f64) -> Self {
         GraphBox3D {
             x_min,
@@ -88,71 +97,109 @@ pub enum QuadTreeNode {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QuadTreeRootNode {
-    children: [QuadTreeNode; 4],
+    pub(crate) children: [QuadTreeNode; 4],
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QuadTreeLeafNode {
-    edge_points: Vec<Point2D>,
-    vertex: Point2D,
+    // Each separate piece of curve through the leaf, as the edge points it
+    // joins up and the vertex they join at
+    pieces: Vec<(Vec<Point2D>, Point2D)>,
+    // Where the function changes sign across a pole instead of a zero
+    pole_points: Vec<Point2D>,
+}
+
+// What a graph is drawn in terms of besides the window: the variables on the
+// horizontal and vertical axes, the values of the rest, and how much work it
+// can do. It gets passed down through every step of the graphing.
+#[derive(Clone, Copy)]
+pub struct GraphContext<'a> {
+    pub var1: &'a str,
+    pub var2: &'a str,
+    pub var_values: &'a HashMap<String, f64>,
+    pub budget: &'a EvaluationBudget,
+}
+
+impl<'a> GraphContext<'a> {
+    pub fn new(
+        var1: &'a str,
+        var2: &'a str,
+        var_values: &'a HashMap<String, f64>,
+        budget: &'a EvaluationBudget,
+    ) -> Self {
+        GraphContext {
+            var1,
+            var2,
+            var_values,
+            budget,
+        }
+    }
+
+    // The same values and budget, with other variables on the axes
+    pub fn with_axes(&self, var1: &'a str, var2: &'a str) -> Self {
+        GraphContext {
+            var1,
+            var2,
+            ..*self
+        }
+    }
 }
 
 pub fn graph_equation_2d(
-    var1: &String,
-    var2: &String,
+    var1: &str,
+    var2: &str,
     window: &GraphBox,
     equation: &Equation,
     depth: i64,
     search_depth: i64,
+    adaptive: Option<&AdaptiveRefinement>,
+    refinement: &EdgeRefinement,
     var_values: &HashMap<String, f64>,
-) -> Result<Vec<Contour2D>, String> {
-    if let Some(var) = equation.left.as_any().downcast_ref::<Variable>() {
-        if &var.name == var1 {
-            if equation.right.count_var_instances(var1) == 0 {
-                return graph_function_2d(
-                    var2.to_string(),
-                    window,
-                    equation.right.clone(),
-                    var_values,
-                    true,
-                );
-            }
-        }
-        if &var.name == var2 {
-            if equation.right.count_var_instances(var2) == 0 {
-                return graph_function_2d(
-                    var1.to_string(),
-                    window,
-                    equation.right.clone(),
-                    var_values,
-                    false,
-                );
-            }
-        }
-    }
-    if let Some(var) = equation.right.as_any().downcast_ref::<Variable>() {
-        if &var.name == var1 {
-            if equation.left.count_var_instances(var1) == 0 {
-                return graph_function_2d(
-                    var2.to_string(),
-                    window,
-                    equation.left.clone(),
-                    var_values,
-                    true,
-                );
-            }
-        }
-        if &var.name == var2 {
-            if equation.left.count_var_instances(var2) == 0 {
-                return graph_function_2d(
-                    var1.to_string(),
-                    window,
-                    equation.left.clone(),
-                    var_values,
-                    false,
-                );
-            }
-        }
+) -> Result<Vec<Polyline2D>, String> {
+    graph_equation_with_asymptotes_2d(
+        &GraphContext::new(var1, var2, var_values, &EvaluationBudget::unlimited()),
+        window,
+        equation,
+        depth,
+        search_depth,
+        adaptive,
+        refinement,
+    )
+    .map(|(contours, _)| contours)
+}
+
+// Like graph_equation_2d, but also returns the vertical asymptotes (or any
+// other curves the equation's sign changes across without being zero). For
+// explicit functions, these are the poles the function jumps across. Once the
+// budget runs out, cells stop getting split, so the curves come out coarser.
+pub fn graph_equation_with_asymptotes_2d(
+    context: &GraphContext,
+    window: &GraphBox,
+    equation: &Equation,
+    depth: i64,
+    search_depth: i64,
+    adaptive: Option<&AdaptiveRefinement>,
+    refinement: &EdgeRefinement,
+) -> Result<(Vec<Polyline2D>, Vec<Polyline2D>), String> {
+    let GraphContext {
+        var1,
+        var2,
+        var_values,
+        budget,
+    } = *context;
+    if let Some((var, expression, flipped)) = get_explicit_function_2d(var1, var2, equation) {
+        let (contours, asymptotes) =
+            graph_function_2d(var, window, expression, var_values, flipped)?;
+        let to_polylines = |contours: Vec<Contour2D>| {
+            contours
+                .into_iter()
+                .map(|points| Polyline2D {
+                    points,
+                    closed: false,
+                })
+                .collect()
+        };
+        return Ok((to_polylines(contours), to_polylines(asymptotes)));
     }
 
     let expression = Plus::new(vec![
@@ -160,6 +207,61 @@ pub fn graph_equation_2d(
         Box::new(Minus::new(equation.right.clone())),
     ]);
 
+    check_variables_2d(&expression, var1, var2, var_values)?;
+
+    // Corners get shared between cells, and looked at again when stitching
+    let samples = SampleCache::for_area(window);
+    let evaluate = get_evaluator_2d(&expression, var1, var2, var_values);
+    let evaluate = |p: Point2D| {
+        budget.spend(1);
+        evaluate(p)
+    };
+    sample_tree_corners_2d(&expression, context, window, search_depth, &samples)?;
+    let f = |p: Point2D| samples.get_or_evaluate(p, &evaluate);
+
+    let evaluate_gradient = get_gradient_evaluator_2d(&expression, var1, var2, var_values);
+    let evaluate_gradient = get_cheating_gradient(&evaluate_gradient);
+    let gradients = SampleCache::<Vec2D>::for_area(window);
+    let df = |p: Point2D| gradients.get_or_evaluate(p, &evaluate_gradient);
+
+    let exclusions = expression.get_real_domain().get_exclusions();
+    let exclusions: Vec<_> = exclusions
+        .iter()
+        .map(|exclusion| get_evaluator_2d(&**exclusion, var1, var2, var_values))
+        .collect();
+    let exclusions: Vec<&dyn Fn(Point2D) -> f64> = exclusions
+        .iter()
+        .map(|exclusion| exclusion as &dyn Fn(Point2D) -> f64)
+        .collect();
+
+    let mut tree = match adaptive {
+        Some(adaptive) => {
+            let mut tree = build_tree(search_depth, search_depth, window, &f, &df, budget);
+            refine_tree(
+                &mut tree,
+                window,
+                depth - search_depth,
+                adaptive,
+                &f,
+                &df,
+                budget,
+            );
+            tree
+        }
+        None => build_tree(depth, search_depth, window, &f, &df, budget),
+    };
+    stitch_tree(&mut tree, window, &f, &df, &exclusions, refinement);
+
+    Ok((get_contours_2d(&tree, &df), get_asymptotes_2d(&tree, &df)))
+}
+
+// Makes sure every variable in the expression is either an axis or has a value
+pub(crate) fn check_variables_2d(
+    expression: &dyn Expression,
+    var1: &str,
+    var2: &str,
+    var_values: &HashMap<String, f64>,
+) -> Result<(), String> {
     let variables = expression.get_variables();
     if variables
         .iter()
@@ -180,16 +282,51 @@ pub fn graph_equation_2d(
         ));
     }
 
-    let f = |Point2D(x, y)| {
+    Ok(())
+}
+
+// Evaluates the expression with var1 and var2 set to a point's coordinates.
+// (Call check_variables_2d first, since missing variables panic.)
+pub(crate) fn get_evaluator_2d<'a>(
+    expression: &'a dyn Expression,
+    var1: &'a str,
+    var2: &'a str,
+    var_values: &'a HashMap<String, f64>,
+) -> impl Fn(Point2D) -> f64 + 'a {
+    move |Point2D(x, y)| {
         let mut variables = var_values.clone();
         variables.insert(var1.to_string(), x);
         variables.insert(var2.to_string(), y);
         expression.evaluate(&variables).unwrap()
-    };
+    }
+}
+
+// Evaluates the gradient of the expression with respect to var1 and var2
+pub(crate) fn get_gradient_evaluator_2d<'a>(
+    expression: &dyn Expression,
+    var1: &'a str,
+    var2: &'a str,
+    var_values: &'a HashMap<String, f64>,
+) -> impl Fn(Point2D) -> Vec2D + 'a {
+    get_gradient_evaluator_from_derivatives_2d(
+        expression.derivative(var1).basic_simplify(),
+        expression.derivative(var2).basic_simplify(),
+        var1,
+        var2,
+        var_values,
+    )
+}
 
-    let dx = expression.derivative(var1).basic_simplify();
-    let dy = expression.derivative(var2).basic_simplify();
-    let df = |Point2D(x, y)| {
+// Same as get_gradient_evaluator_2d, for when the partial derivatives have
+// already been worked out
+pub(crate) fn get_gradient_evaluator_from_derivatives_2d<'a>(
+    dx: Box<dyn Expression>,
+    dy: Box<dyn Expression>,
+    var1: &'a str,
+    var2: &'a str,
+    var_values: &'a HashMap<String, f64>,
+) -> impl Fn(Point2D) -> Vec2D + 'a {
+    move |Point2D(x, y)| {
         let mut variables = var_values.clone();
         variables.insert(var1.to_string(), x);
         variables.insert(var2.to_string(), y);
@@ -197,105 +334,98 @@ pub fn graph_equation_2d(
             dx.evaluate(&variables).unwrap(),
             dy.evaluate(&variables).unwrap(),
         )
-    };
-    let df = get_cheating_gradient(&df);
-
-    let tree = build_tree(depth, search_depth, window, &f, &df, var1, var2);
-
-    Ok(get_contours_2d(&tree))
+    }
 }
 
-pub fn graph_equation_3d(
-    var1: &String,
-    var2: &String,
-    var3: &String,
-    window: &GraphBox3D,
+// If the equation is solved for one of the axis variables (like y = x^2 or
+// x = sin(y)), returns the other variable, the expression in terms of it, and
+// whether the result should be flipped to put the input on the vertical axis.
+pub(crate) fn get_explicit_function_2d(
+    var1: &str,
+    var2: &str,
     equation: &Equation,
-    var_values: &HashMap<String, f64>,
-) -> Result<Vec<Triangle3D>, String> {
-    if let Some(var) = equation.left.as_any().downcast_ref::<Variable>() {
-        if &var.name == var1 {
-            if equation.right.count_var_instances(var1) == 0 {
-                return graph_function_3d(
-                    var2.to_string(),
-                    var3.to_string(),
-                    window,
-                    equation.right.clone(),
-                    var_values,
-                );
-            }
-        }
-        if &var.name == var2 {
-            if equation.right.count_var_instances(var2) == 0 {
-                return graph_function_3d(
-                    var1.to_string(),
-                    var3.to_string(),
-                    window,
-                    equation.right.clone(),
-                    var_values,
-                );
+) -> Option<(String, Box<dyn Expression>, bool)> {
+    for (side, other_side) in [
+        (&equation.left, &equation.right),
+        (&equation.right, &equation.left),
+    ] {
+        if let Some(var) = side.as_any().downcast_ref::<Variable>() {
+            if var.name == var1 && other_side.count_var_instances(var1) == 0 {
+                return Some((var2.to_string(), other_side.clone(), true));
             }
-        }
-        if &var.name == var3 {
-            if equation.right.count_var_instances(var3) == 0 {
-                return graph_function_3d(
-                    var1.to_string(),
-                    var2.to_string(),
-                    window,
-                    equation.right.clone(),
-                    var_values,
-                );
+            if var.name == var2 && other_side.count_var_instances(var2) == 0 {
+                return Some((var1.to_string(), other_side.clone(), false));
             }
         }
     }
-    if let Some(var) = equation.right.as_any().downcast_ref::<Variable>() {
-        if &var.name == var1 {
-            if equation.left.count_var_instances(var1) == 0 {
-                return graph_function_3d(
-                    var2.to_string(),
-                    var3.to_string(),
-                    window,
-                    equation.left.clone(),
-                    var_values,
-                );
-            }
-        }
-        if &var.name == var2 {
-            if equation.left.count_var_instances(var2) == 0 {
-                return graph_function_3d(
-                    var1.to_string(),
-                    var3.to_string(),
-                    window,
-                    equation.left.clone(),
-                    var_values,
-                );
-            }
-        }
-        if &var.name == var3 {
-            if equation.left.count_var_instances(var3) == 0 {
-                return graph_function_3d(
-                    var1.to_string(),
-                    var2.to_string(),
-                    window,
-                    equation.left.clone(),
-                    var_values,
-                );
+
+    None
+}
+
+// If the equation is solved for one of the axis variables (like z = x^2 + y^2),
+// returns the other two variables, the one it's solved for, and the expression
+// in terms of the other two
+pub(crate) fn get_explicit_function_3d(
+    var1: &str,
+    var2: &str,
+    var3: &str,
+    equation: &Equation,
+) -> Option<(String, String, String, Box<dyn Expression>)> {
+    for (side, other_side) in [
+        (&equation.left, &equation.right),
+        (&equation.right, &equation.left),
+    ] {
+        if let Some(var) = side.as_any().downcast_ref::<Variable>() {
+            for (output, input1, input2) in
+                [(var1, var2, var3), (var2, var1, var3), (var3, var1, var2)]
+            {
+                if var.name == output && other_side.count_var_instances(output) == 0 {
+                    return Some((
+                        input1.to_string(),
+                        input2.to_string(),
+                        output.to_string(),
+                        other_side.clone(),
+                    ));
+                }
             }
         }
     }
 
-    Err("Cannot plot 3D equation that is not solved in terms of one variable".to_string())
+    None
+}
+
+// The context's axes are x and y, and var3 is z
+pub fn graph_equation_3d(
+    context: &GraphContext,
+    var3: &str,
+    window: &GraphBox3D,
+    equation: &Equation,
+    refinement: &SurfaceRefinement,
+) -> Result<Vec<Triangle3D>, String> {
+    match get_explicit_function_3d(context.var1, context.var2, var3, equation) {
+        Some((input1, input2, _, expression)) => graph_function_3d(
+            &context.with_axes(&input1, &input2),
+            window,
+            expression,
+            refinement,
+        ),
+        None => {
+            Err("Cannot plot 3D equation that is not solved in terms of one variable".to_string())
+        }
+    }
 }
 
+// Samples the function across the window, starting a new contour wherever it's
+// undefined or jumps, so the pieces either side of a pole or a step don't get
+// joined up. Also returns a line across the window at each pole it jumps over.
 pub fn graph_function_2d(
     var: String,
     window: &GraphBox,
     expression: Box<dyn Expression>,
     var_values: &HashMap<String, f64>,
     flipped: bool,
-) -> Result<Vec<Contour2D>, String> {
-    let mut contours = vec![];
-    let mut contour: Contour2D = vec![];
+) -> Result<(Vec<Contour2D>, Vec<Contour2D>), String> {
+    let mut samples = Vec::with_capacity(501);
 
     // log(format!(
     //     "Domain: {:?}",
@@ -305,6 +435,11 @@ pub fn graph_function_2d(
     let mut variables = var_values.clone();
     use rand::Rng;
     let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(0);
+    let (t_min, t_max) = if flipped {
+        (window.y_min, window.y_max)
+    } else {
+        (window.x_min, window.x_max)
+    };
     for i in 0..501 {
         // In high-frequency graphs, taking regular samples can cause patterns to
         // appear that are not actually there. (For example, when graphing y = sin(x^2)
@@ -315,163 +450,1273 @@ pub fn graph_function_2d(
             } else {
                 rng.gen::<f64>() - 0.5
             };
-        let x = window.x_min + (window.x_max - window.x_min) * randomized_i / 500.0;
-        variables.insert(var.clone(), x);
+        let t = t_min + (t_max - t_min) * randomized_i / 500.0;
+        variables.insert(var.clone(), t);
 
-        let y = expression.evaluate(&variables)?;
+        samples.push((t, expression.evaluate(&variables)?));
+    }
 
-        if y.is_finite() {
-            if flipped {
-                contour.push(Point2D(y, x));
-            } else {
-                contour.push(Point2D(x, y));
+    let at = |expression: &dyn Expression, t: f64| {
+        let mut variables = var_values.clone();
+        variables.insert(var.clone(), t);
+        expression.evaluate(&variables).unwrap_or(f64::NAN)
+    };
+    let f = |t| at(&*expression, t);
+    let exclusions = expression.get_real_domain().get_exclusions();
+
+    let to_point = |t: f64, value: f64| {
+        if flipped {
+            Point2D(value, t)
+        } else {
+            Point2D(t, value)
+        }
+    };
+    let (value_min, value_max) = if flipped {
+        (window.x_min, window.x_max)
+    } else {
+        (window.y_min, window.y_max)
+    };
+
+    // Only the biggest jumps around, and the tops of peaks (like 1/x^2 at 0),
+    // need checking for discontinuities, since a steep but continuous stretch
+    // of curve makes its neighbours jump too
+    let jumps: Vec<f64> = samples
+        .windows(2)
+        .map(|w| (w[1].1 - w[0].1).abs())
+        .collect();
+    let is_biggest_jump = |i: usize| {
+        let bigger =
+            |other: Option<&f64>| other.is_none_or(|&other| other.is_nan() || other < jumps[i]);
+        bigger(i.checked_sub(1).and_then(|i| jumps.get(i))) && bigger(jumps.get(i + 1))
+    };
+    let is_peak = |i: usize| {
+        let top = samples[i].1.abs().min(samples[i + 1].1.abs());
+        let lower = |other: Option<&(f64, f64)>| {
+            other.is_none_or(|&(_, other)| other.is_nan() || other.abs() < top)
+        };
+        lower(i.checked_sub(1).map(|i| &samples[i])) && lower(samples.get(i + 2))
+    };
+
+    let mut contours = vec![];
+    let mut asymptotes = vec![];
+    let mut contour: Contour2D = vec![];
+    for (i, &(t, value)) in samples.iter().enumerate() {
+        if !value.is_finite() {
+            if !contour.is_empty() {
+                contours.push(std::mem::take(&mut contour));
+            }
+            continue;
+        }
+
+        if let Some(&(previous_t, previous_value)) = i.checked_sub(1).map(|i| &samples[i]) {
+            // Poles where an exclusion from the domain changes sign, like 1/x at
+            // 0, are found exactly. Holes, like sin(x)/x at 0, are left joined.
+            let step = t - previous_t;
+            let excluded = exclusions.iter().find_map(|exclusion| {
+                let g = |t| at(&**exclusion, t);
+                if g(previous_t) * g(t) < 0.0 {
+                    Some(refine_bracketed_root(&g, None, previous_t, t))
+                        .filter(|&root| is_pole(&f, root, step))
+                } else {
+                    None
+                }
+            });
+            let discontinuity = excluded.or_else(|| {
+                if !previous_value.is_finite() {
+                    None
+                } else if is_biggest_jump(i - 1) {
+                    find_jump(&f, previous_t, previous_value, t, value)
+                } else if is_peak(i - 1) {
+                    find_peak(&f, previous_t, t).filter(|&peak| is_pole(&f, peak, step))
+                } else {
+                    None
+                }
+            });
+
+            if let Some(discontinuity) = discontinuity {
+                if !contour.is_empty() {
+                    contours.push(std::mem::take(&mut contour));
+                }
+
+                if is_pole(&f, discontinuity, step) {
+                    asymptotes.push(vec![
+                        to_point(discontinuity, value_min),
+                        to_point(discontinuity, value_max),
+                    ]);
+                }
+            }
+        }
+
+        contour.push(to_point(t, value));
+    }
+    if !contour.is_empty() {
+        contours.push(contour);
+    }
+
+    Ok((contours, asymptotes))
+}
+
+// Don't narrow a jump down more than this many times
+const JUMP_STEPS: u32 = 40;
+
+// Narrows down where f jumps between a and b, following the sign change if
+// there is one, and otherwise whichever half f varies more over (going by the
+// value in the middle of each half). If f is continuous the jump shrinks away,
+// but at a discontinuity it doesn't, so then this returns where it is.
+fn find_jump(
+    f: &dyn Fn(f64) -> f64,
+    mut a: f64,
+    mut f_a: f64,
+    mut b: f64,
+    mut f_b: f64,
+) -> Option<f64> {
+    let variation =
+        |f_a: f64, f_middle: f64, f_b: f64| (f_middle - f_a).abs() + (f_b - f_middle).abs();
+
+    let mut middle = (a + b) / 2.0;
+    let mut f_middle = f(middle);
+    let start = variation(f_a, f_middle, f_b);
+    for _ in 0..JUMP_STEPS {
+        let (left, right) = ((a + middle) / 2.0, (middle + b) / 2.0);
+        let (f_left, f_right) = (f(left), f(right));
+        for (t, value) in [(middle, f_middle), (left, f_left), (right, f_right)] {
+            if !value.is_finite() {
+                return Some(t);
             }
         }
+
+        let go_left = if f_a * f_middle < 0.0 {
+            true
+        } else if f_middle * f_b < 0.0 {
+            false
+        } else {
+            variation(f_a, f_left, f_middle) > variation(f_middle, f_right, f_b)
+        };
+        if go_left {
+            (b, f_b, middle, f_middle) = (middle, f_middle, left, f_left);
+        } else {
+            (a, f_a, middle, f_middle) = (middle, f_middle, right, f_right);
+        }
+    }
+
+    let remaining = variation(f_a, f_middle, f_b);
+    if remaining > 0.0 && remaining >= start / 4.0 {
+        Some(middle)
+    } else {
+        None
     }
+}
 
-    contours.push(contour);
+// Where |f| is biggest between a and b, by golden section search
+fn find_peak(f: &dyn Fn(f64) -> f64, mut a: f64, mut b: f64) -> Option<f64> {
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let mut c = b - ratio * (b - a);
+    let mut d = a + ratio * (b - a);
+    let (mut f_c, mut f_d) = (f(c).abs(), f(d).abs());
+    for _ in 0..2 * JUMP_STEPS {
+        if !f_c.is_finite() {
+            return Some(c);
+        }
+        if !f_d.is_finite() {
+            return Some(d);
+        }
+        if f_c > f_d {
+            (b, d, f_d) = (d, c, f_c);
+            c = b - ratio * (b - a);
+            f_c = f(c).abs();
+        } else {
+            (a, c, f_c) = (c, d, f_d);
+            d = a + ratio * (b - a);
+            f_d = f(d).abs();
+        }
+    }
 
-    Ok(contours)
+    Some((a + b) / 2.0)
 }
 
-pub fn graph_function_3d(
-    var1: String,
-    var2: String,
-    window: &GraphBox3D,
+// Whether f blows up on both sides of t, which is a pole rather than a jump or
+// a tall but smooth peak if it keeps getting bigger the closer we look. Step
+// is the spacing of the samples around t.
+fn is_pole(f: &dyn Fn(f64) -> f64, t: f64, step: f64) -> bool {
+    let h = step.abs() * 1e-6;
+    [-h, h].iter().all(|&h| {
+        let near = f(t + h).abs();
+        near.is_infinite() || near > 10.0 * f(t + 1000.0 * h).abs()
+    })
+}
+
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PointOfInterestKind {
+    Zero = 0,
+    Minimum = 1,
+    Maximum = 2,
+    Inflection = 3,
+    // Where the graph of y = f(x) crosses the y-axis
+    YIntercept = 4,
+    // A removable discontinuity, like x = 0 in y = sin(x)/x
+    Hole = 5,
+    // Where the graph of x = f(y) crosses the x-axis
+    XIntercept = 6,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct PointOfInterest2D {
+    pub kind: PointOfInterestKind,
+    pub point: Point2D,
+}
+
+pub fn get_equation_points_of_interest_2d(
+    var1: &str,
+    var2: &str,
+    window: &GraphBox,
+    equation: &Equation,
+    var_values: &HashMap<String, f64>,
+) -> Result<Vec<PointOfInterest2D>, String> {
+    if let Some((var, expression, flipped)) = get_explicit_function_2d(var1, var2, equation) {
+        return get_function_points_of_interest_2d(var, window, expression, var_values, flipped);
+    }
+
+    Err("Points of interest can only be found for equations of the form y = f(x)".to_string())
+}
+
+pub fn get_function_points_of_interest_2d(
+    var: String,
+    window: &GraphBox,
     expression: Box<dyn Expression>,
     var_values: &HashMap<String, f64>,
-) -> Result<Vec<Triangle3D>, String> {
-    const DIVISIONS_X: usize = 30;
-    const DIVISIONS_Y: usize = 30;
+    flipped: bool,
+) -> Result<Vec<PointOfInterest2D>, String> {
+    const SAMPLES: usize = 500;
+
+    if let Some(missing) = expression
+        .get_variables()
+        .iter()
+        .find(|v| *v != &var && !var_values.contains_key(*v))
+    {
+        return Err(format!("No value for variable {}", missing));
+    }
 
-    let mut values = [[0.0; DIVISIONS_X + 1]; DIVISIONS_Y + 1];
+    let (t_min, t_max) = if flipped {
+        (window.y_min, window.y_max)
+    } else {
+        (window.x_min, window.x_max)
+    };
+    let samples: Vec<f64> = (0..=SAMPLES)
+        .map(|i| t_min + (t_max - t_min) * i as f64 / SAMPLES as f64)
+        .collect();
 
-    let mut variables = var_values.clone();
-    for i in 0..(DIVISIONS_X + 1) {
-        for j in 0..(DIVISIONS_Y + 1) {
-            // In high-frequency graphs, taking regular samples can cause patterns to
-            // appear that are not actually there. (For example, when graphing y = sin(x^2)
-            // and zooming out.) To avoid this, we randomize the sample point slightly.
-            let x = window.x_min + (window.x_max - window.x_min) * i as f64 / (DIVISIONS_X as f64);
-            let y = window.y_min + (window.y_max - window.y_min) * j as f64 / (DIVISIONS_Y as f64);
-            variables.insert(var1.clone(), x);
-            variables.insert(var2.clone(), y);
+    let d1 = expression.derivative(&var).basic_simplify();
+    let d2 = d1.derivative(&var).basic_simplify();
+
+    let at = |expression: &dyn Expression, t: f64| {
+        let mut variables = var_values.clone();
+        variables.insert(var.clone(), t);
+        expression.evaluate(&variables).unwrap_or(f64::NAN)
+    };
+    let f = |t| at(&*expression, t);
+    let df = |t| at(&*d1, t);
+    let ddf = |t| at(&*d2, t);
+
+    let to_point = |t: f64, value: f64| {
+        if flipped {
+            Point2D(value, t)
+        } else {
+            Point2D(t, value)
+        }
+    };
+
+    let mut points = vec![];
 
-            let z = expression.evaluate(&variables)?;
-            values[i][j] = z;
+    for (t, _) in find_roots_on_samples(&f, Some(&df), &samples, false) {
+        points.push(PointOfInterest2D {
+            kind: PointOfInterestKind::Zero,
+            point: to_point(t, 0.0),
+        });
+    }
+
+    for (t, direction) in find_roots_on_samples(&df, Some(&ddf), &samples, true) {
+        let value = f(t);
+        if value.is_finite() {
+            points.push(PointOfInterest2D {
+                kind: if direction > 0.0 {
+                    PointOfInterestKind::Minimum
+                } else {
+                    PointOfInterestKind::Maximum
+                },
+                point: to_point(t, value),
+            });
         }
     }
 
-    let mut triangles = vec![];
-    for i in 0..DIVISIONS_X {
-        for j in 0..DIVISIONS_Y {
-            let min_x =
-                window.x_min + (window.x_max - window.x_min) * i as f64 / (DIVISIONS_X as f64);
-            let min_y =
-                window.y_min + (window.y_max - window.y_min) * j as f64 / (DIVISIONS_Y as f64);
-            let max_x = window.x_min
-                + (window.x_max - window.x_min) * (i + 1) as f64 / (DIVISIONS_X as f64);
-            let max_y = window.y_min
-                + (window.y_max - window.y_min) * (j + 1) as f64 / (DIVISIONS_Y as f64);
+    for (t, _) in find_roots_on_samples(&ddf, None, &samples, true) {
+        let value = f(t);
+        if value.is_finite() {
+            points.push(PointOfInterest2D {
+                kind: PointOfInterestKind::Inflection,
+                point: to_point(t, value),
+            });
+        }
+    }
 
-            let a = Point3D(min_x, min_y, values[i][j]);
-            let b = Point3D(max_x, min_y, values[i + 1][j]);
-            let c = Point3D(min_x, max_y, values[i][j + 1]);
-            let d = Point3D(max_x, max_y, values[i + 1][j + 1]);
+    if t_min <= 0.0 && 0.0 <= t_max {
+        let value = f(0.0);
+        if value.is_finite() {
+            points.push(PointOfInterest2D {
+                kind: if flipped {
+                    PointOfInterestKind::XIntercept
+                } else {
+                    PointOfInterestKind::YIntercept
+                },
+                point: to_point(0.0, value),
+            });
+        }
+    }
 
-            if a.2.is_finite() && b.2.is_finite() && c.2.is_finite() {
-                triangles.push(Triangle3D(c, a, b));
+    // Holes can only appear where the expression is excluded from its domain, so
+    // we look for zeros of each exclusion and check whether the function
+    // approaches the same value from both sides.
+    let h = (t_max - t_min) * 1e-6;
+    let mut hole_locations: Vec<f64> = vec![];
+    for exclusion in expression.get_real_domain().get_exclusions() {
+        let g = |t| at(&*exclusion, t);
+        for (t, _) in find_roots_on_samples(&g, None, &samples, false) {
+            if hole_locations.iter().any(|other| (other - t).abs() <= h) {
+                continue;
             }
-            if b.2.is_finite() && c.2.is_finite() && d.2.is_finite() {
-                triangles.push(Triangle3D(b, d, c));
+
+            // Extrapolate linearly towards t from each side
+            let left = 2.0 * f(t - h) - f(t - 2.0 * h);
+            let right = 2.0 * f(t + h) - f(t + 2.0 * h);
+            if left.is_finite()
+                && right.is_finite()
+                && (left - right).abs() <= 1e-4 * (1.0 + left.abs() + right.abs())
+            {
+                hole_locations.push(t);
+                points.push(PointOfInterest2D {
+                    kind: PointOfInterestKind::Hole,
+                    point: to_point(t, (left + right) / 2.0),
+                });
             }
         }
     }
 
-    Ok(triangles)
+    Ok(points)
 }
 
-fn build_tree(
-    depth: i64,
-    search_depth: i64,
-    area: &GraphBox,
-    f: &impl Fn(Point2D) -> f64,
-    df: &impl Fn(Point2D) -> Vec2D,
-    var1: &str,
-    var2: &str,
-) -> QuadTreeNode {
-    let vertex_values = [
-        f(Point2D(area.x_min, area.y_min)),
-        f(Point2D(area.x_max, area.y_min)),
-        f(Point2D(area.x_min, area.y_max)),
-        f(Point2D(area.x_max, area.y_max)),
-    ];
+// Finds the zeros of g between consecutive samples, returning each root along
+// with the sign of g just after it (so +1.0 means g is increasing through the
+// root). Sign changes caused by poles rather than zeros are discarded.
+fn find_roots_on_samples(
+    g: &dyn Fn(f64) -> f64,
+    dg: Option<&dyn Fn(f64) -> f64>,
+    samples: &[f64],
+    require_sign_change: bool,
+) -> Vec<(f64, f64)> {
+    let values: Vec<f64> = samples.iter().map(|&t| g(t)).collect();
+
+    let mut roots = vec![];
+    for i in 0..samples.len() {
+        if values[i] == 0.0 {
+            let before = if i > 0 { values[i - 1] } else { f64::NAN };
+            let after = values.get(i + 1).copied().unwrap_or(f64::NAN);
+            if before * after < 0.0 {
+                roots.push((samples[i], after.signum()));
+            } else if !require_sign_change {
+                roots.push((samples[i], 0.0));
+            }
+            continue;
+        }
 
-    if search_depth <= 0 {
-        // If we're below the search depth, check the vertex values
-        // and stop if they look boring.
-        if vertex_values.iter().all(|value| value == &0.0) {
-            return QuadTreeNode::Zero;
+        if i + 1 < samples.len() && values[i] * values[i + 1] < 0.0 {
+            let root = refine_bracketed_root(g, dg, samples[i], samples[i + 1]);
+            if g(root).abs() <= values[i].abs().min(values[i + 1].abs()) {
+                roots.push((root, values[i + 1].signum()));
+            }
         }
-        if vertex_values.iter().all(|value| value < &0.0) {
-            return QuadTreeNode::Negative;
+    }
+
+    roots
+}
+
+// Narrows a sign change of g on [a, b] down to a root, taking Newton steps
+// where they land inside the bracket and bisecting otherwise.
+fn refine_bracketed_root(
+    g: &dyn Fn(f64) -> f64,
+    dg: Option<&dyn Fn(f64) -> f64>,
+    mut a: f64,
+    mut b: f64,
+) -> f64 {
+    let tolerance = (b - a).abs() * 1e-10;
+    let mut g_a = g(a);
+    let mut t = (a + b) / 2.0;
+    for _ in 0..64 {
+        let g_t = g(t);
+        if g_t == 0.0 || !g_t.is_finite() {
+            return t;
         }
-        if vertex_values.iter().all(|value| value >= &0.0) {
-            return QuadTreeNode::Positive;
+        if g_a * g_t < 0.0 {
+            b = t;
+        } else {
+            a = t;
+            g_a = g_t;
         }
 
-        // If we reach this point, the node looks interesting. But if we've hit the bottom
-        // of the tree, we have to stop anyway.
-        if depth <= 0 {
-            let (edge_points, vertex) = get_leaf_key_points(area, &f, &df);
+        let next = match dg.map(|dg| t - g_t / dg(t)) {
+            Some(newton) if newton > a.min(b) && newton < a.max(b) => newton,
+            _ => (a + b) / 2.0,
+        };
+        if (next - t).abs() <= tolerance {
+            return next;
+        }
+        t = next;
+    }
 
-            return QuadTreeNode::Leaf(QuadTreeLeafNode {
-                edge_points,
-                vertex,
-            });
+    t
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShadedArea2D {
+    // Closed polygons covering the region between the curves. The region is
+    // split wherever the curves cross, so each polygon is entirely on one side.
+    pub polygons: Vec<Contour2D>,
+    // Signed area (the integral of top - bottom from lower to upper)
+    pub area: f64,
+}
+
+pub fn graph_area_2d(
+    var: String,
+    top: Box<dyn Expression>,
+    bottom: Option<Box<dyn Expression>>,
+    lower: f64,
+    upper: f64,
+    var_values: &HashMap<String, f64>,
+) -> Result<ShadedArea2D, String> {
+    const SAMPLES: usize = 500;
+
+    let bottom = bottom.unwrap_or_else(|| Box::new(Constant::new(0.0)));
+
+    let mut variables = var_values.clone();
+    variables.insert(var.clone(), lower);
+
+    let area = integrate(
+        &mut |t| {
+            *variables.get_mut(&var).unwrap() = t;
+            Ok(top.evaluate(&variables)? - bottom.evaluate(&variables)?)
+        },
+        lower,
+        upper,
+    )?;
+
+    let mut polygons = vec![];
+    let mut top_points: Contour2D = vec![];
+    let mut bottom_points: Contour2D = vec![];
+
+    fn close_polygon(
+        polygons: &mut Vec<Contour2D>,
+        top_points: &mut Contour2D,
+        bottom_points: &mut Contour2D,
+    ) {
+        if top_points.len() >= 2 {
+            let mut polygon = std::mem::take(top_points);
+            polygon.extend(bottom_points.drain(..).rev());
+            polygons.push(polygon);
         }
+        top_points.clear();
+        bottom_points.clear();
     }
 
-    // Either this node looks interesting or we haven't searched enough yet.
-    // Let's make this a root node and keep going.
-    let children = [
-        build_tree(
-            depth - 1,
-            search_depth - 1,
-            &area.get_quadrant(0),
-            f,
-            df,
-            var1,
-            var2,
-        ),
-        build_tree(
-            depth - 1,
-            search_depth - 1,
-            &area.get_quadrant(1),
-            f,
-            df,
-            var1,
-            var2,
-        ),
-        build_tree(
-            depth - 1,
-            search_depth - 1,
-            &area.get_quadrant(2),
-            f,
-            df,
-            var1,
-            var2,
-        ),
-        build_tree(
+    let mut previous: Option<(f64, f64, f64)> = None;
+    for i in 0..=SAMPLES {
+        let t = lower + (upper - lower) * i as f64 / SAMPLES as f64;
+        *variables.get_mut(&var).unwrap() = t;
+        let top_value = top.evaluate(&variables)?;
+        let bottom_value = bottom.evaluate(&variables)?;
+
+        if !top_value.is_finite() || !bottom_value.is_finite() {
+            close_polygon(&mut polygons, &mut top_points, &mut bottom_points);
+            previous = None;
+            continue;
+        }
+
+        if let Some((prev_t, prev_top, prev_bottom)) = previous {
+            let prev_diff = prev_top - prev_bottom;
+            let diff = top_value - bottom_value;
+            if prev_diff * diff < 0.0 {
+                // The curves cross, so finish this polygon at the crossing point
+                // and start the next one from there.
+                let s = prev_diff / (prev_diff - diff);
+                let crossing = Point2D(
+                    prev_t + s * (t - prev_t),
+                    prev_top + s * (top_value - prev_top),
+                );
+                top_points.push(crossing);
+                bottom_points.push(crossing);
+                close_polygon(&mut polygons, &mut top_points, &mut bottom_points);
+                top_points.push(crossing);
+                bottom_points.push(crossing);
+            }
+        }
+
+        top_points.push(Point2D(t, top_value));
+        bottom_points.push(Point2D(t, bottom_value));
+        previous = Some((t, top_value, bottom_value));
+    }
+    close_polygon(&mut polygons, &mut top_points, &mut bottom_points);
+
+    Ok(ShadedArea2D { polygons, area })
+}
+
+// How finely to mesh a surface z = f(x, y). The (x, y) domain starts out as a
+// 2^min_depth by 2^min_depth grid, and cells get split where the surface bends
+// away from them, until they're max_depth splits down.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceRefinement {
+    pub min_depth: u32,
+    pub max_depth: u32,
+    // The most cells the mesh can end up with
+    pub max_cells: usize,
+    // How far the surface can stray from a cell's corners before it gets split,
+    // as a fraction of the window's height
+    pub tolerance: f64,
+}
+
+impl SurfaceRefinement {
+    pub fn new(min_depth: u32, max_depth: u32, max_cells: usize, tolerance: f64) -> Self {
+        SurfaceRefinement {
+            min_depth,
+            max_depth,
+            max_cells,
+            tolerance,
+        }
+    }
+}
+
+impl Default for SurfaceRefinement {
+    fn default() -> Self {
+        SurfaceRefinement::new(4, 7, 4000, 0.002)
+    }
+}
+
+// Any deeper and the cells' coordinates could get too big to work with
+const MAX_SURFACE_DEPTH: u32 = 24;
+
+// How many points to evaluate in one batch
+const SURFACE_BATCH_SIZE: usize = 256;
+
+// A square cell of a surface's (x, y) domain. Coordinates are in units of half
+// the smallest cell, so the middles of cells and their edges are whole numbers too.
+#[derive(Clone, Copy, Debug)]
+struct SurfaceCell {
+    x: i64,
+    y: i64,
+    size: i64,
+    depth: u32,
+}
+
+impl SurfaceCell {
+    // Same order as GraphBox::get_corner
+    fn get_corners(&self) -> [(i64, i64); 4] {
+        let SurfaceCell { x, y, size, .. } = *self;
+        [(x, y), (x + size, y), (x, y + size), (x + size, y + size)]
+    }
+
+    // The middle, then the middles of the bottom, right, top and left edges
+    fn get_midpoints(&self) -> [(i64, i64); 5] {
+        let SurfaceCell { x, y, size, .. } = *self;
+        let half = size / 2;
+        [
+            (x + half, y + half),
+            (x + half, y),
+            (x + size, y + half),
+            (x + half, y + size),
+            (x, y + half),
+        ]
+    }
+
+    // Same order as GraphBox::get_quadrant
+    fn get_quadrant(&self, index: i64) -> SurfaceCell {
+        let half = self.size / 2;
+        SurfaceCell {
+            x: self.x + half * (index % 2),
+            y: self.y + half * (index / 2),
+            size: half,
+            depth: self.depth + 1,
+        }
+    }
+}
+
+// Meshes z = f(x, y), with small triangles where the surface bends and big ones
+// where it's flat. Cells are split where the values in the middle of the cell
+// and its edges are far from what the corners predict, or where the function
+// is only defined on some of the cell. Bends above or below the window don't
+// count, since they get clipped off anyway. Each cell is drawn as a fan around
+// its middle that goes through every corner of its smaller neighbours along its
+// edges, so there are no cracks where big cells meet small ones. The triangles
+// are then trimmed to where the function is defined and clipped to the window.
+pub fn graph_function_3d(
+    context: &GraphContext,
+    window: &GraphBox3D,
+    expression: Box<dyn Expression>,
+    refinement: &SurfaceRefinement,
+) -> Result<Vec<Triangle3D>, String> {
+    let budget = context.budget;
+    let max_depth = refinement.max_depth.min(MAX_SURFACE_DEPTH);
+    let min_depth = refinement.min_depth.min(max_depth);
+    let tolerance = refinement.tolerance * (window.z_max - window.z_min);
+
+    let units = 2_i64 << max_depth;
+    let to_point = |(x, y): (i64, i64)| {
+        Point2D(
+            window.x_min + (window.x_max - window.x_min) * x as f64 / units as f64,
+            window.y_min + (window.y_max - window.y_min) * y as f64 / units as f64,
+        )
+    };
+    let mut values = HashMap::new();
+
+    // Start with a grid of cells, and work out their errors all in one go
+    let cells_across = 1_i64 << min_depth;
+    let mut cells = vec![];
+    for i in 0..cells_across {
+        for j in 0..cells_across {
+            let size = units / cells_across;
+            cells.push(SurfaceCell {
+                x: i * size,
+                y: j * size,
+                size,
+                depth: min_depth,
+            });
+        }
+    }
+    let keys = cells.iter().flat_map(|cell| {
+        let midpoints = if cell.depth < max_depth {
+            cell.get_midpoints().to_vec()
+        } else {
+            vec![]
+        };
+        cell.get_corners().into_iter().chain(midpoints)
+    });
+    sample_surface(
+        &*expression,
+        context,
+        &to_point,
+        keys.collect(),
+        &mut values,
+    )?;
+
+    // Then keep splitting the cell with the biggest error, like refine_tree
+    let mut split = vec![false; cells.len()];
+    let mut leaf_count = cells.len();
+    let mut heap = std::collections::BinaryHeap::new();
+    for (i, cell) in cells.iter().enumerate() {
+        if cell.depth < max_depth {
+            let error = get_surface_cell_error(cell, &values, window.z_min, window.z_max);
+            heap.push((error.to_bits(), i));
+        }
+    }
+
+    while let Some((error, i)) = heap.pop() {
+        if f64::from_bits(error) <= tolerance
+            || leaf_count + 3 > refinement.max_cells
+            || budget.is_exhausted()
+        {
+            break;
+        }
+
+        let children: Vec<_> = (0..4).map(|index| cells[i].get_quadrant(index)).collect();
+        let keys: Vec<_> = children
+            .iter()
+            .filter(|child| child.depth < max_depth)
+            .flat_map(|child| child.get_midpoints())
+            .collect();
+        sample_surface(&*expression, context, &to_point, keys.clone(), &mut values)?;
+        // The budget ran out partway through, so leave the cell as it is
+        if keys.iter().any(|key| !values.contains_key(key)) {
+            break;
+        }
+
+        split[i] = true;
+        leaf_count += 3;
+        for child in children {
+            if child.depth < max_depth {
+                let error = get_surface_cell_error(&child, &values, window.z_min, window.z_max);
+                heap.push((error.to_bits(), cells.len()));
+            }
+            cells.push(child);
+            split.push(false);
+        }
+    }
+
+    let leaves: Vec<_> = cells
+        .iter()
+        .zip(split)
+        .filter(|(_, split)| !split)
+        .map(|(cell, _)| *cell)
+        .collect();
+    // The leaves' corners on each horizontal and vertical line, in order
+    let mut rows: HashMap<i64, std::collections::BTreeSet<i64>> = HashMap::new();
+    let mut columns: HashMap<i64, std::collections::BTreeSet<i64>> = HashMap::new();
+    for (x, y) in leaves.iter().flat_map(|leaf| leaf.get_corners()) {
+        rows.entry(y).or_default().insert(x);
+        columns.entry(x).or_default().insert(y);
+    }
+    // The corners strictly between from and to on a line, going from from
+    let get_between = |line: &std::collections::BTreeSet<i64>, from: i64, to: i64| {
+        let between = line.range(from.min(to) + 1..from.max(to)).copied();
+        if from < to {
+            between.collect::<Vec<_>>()
+        } else {
+            between.rev().collect()
+        }
+    };
+
+    let get_vertex = |key: (i64, i64)| {
+        let Point2D(x, y) = to_point(key);
+        Point3D(x, y, values.get(&key).copied().unwrap_or(f64::NAN))
+    };
+    let mut triangles = vec![];
+    for leaf in leaves {
+        // Go round the cell anticlockwise, picking up the corners of any
+        // smaller cells along the way
+        let [a, b, c, d] = leaf.get_corners();
+        let mut outline = vec![];
+        for (start, end) in [(a, b), (b, d), (d, c), (c, a)] {
+            outline.push(start);
+            if start.1 == end.1 {
+                let between = get_between(&rows[&start.1], start.0, end.0);
+                outline.extend(between.into_iter().map(|x| (x, start.1)));
+            } else {
+                let between = get_between(&columns[&start.0], start.1, end.1);
+                outline.extend(between.into_iter().map(|y| (start.0, y)));
+            }
+        }
+
+        if outline.len() == 4 {
+            let [a, b, c, d] = [a, b, c, d].map(get_vertex);
+            triangles.push(Triangle3D(c, a, b));
+            triangles.push(Triangle3D(b, d, c));
+        } else {
+            let middle = get_vertex(leaf.get_midpoints()[0]);
+            for k in 0..outline.len() {
+                triangles.push(Triangle3D(
+                    middle,
+                    get_vertex(outline[k]),
+                    get_vertex(outline[(k + 1) % outline.len()]),
+                ));
+            }
+        }
+    }
+
+    let triangles = trim_to_domain(triangles, &*expression, context)?;
+    Ok(triangles
+        .into_iter()
+        .flat_map(|triangle| clip_triangle_3d(triangle, window))
+        .collect())
+}
+
+// Steps of bisection to take to find where an edge leaves the function's domain
+const DOMAIN_EDGE_STEPS: u32 = 12;
+
+// Cuts the corners where the function isn't defined off the triangles, at
+// about where each edge leaves the domain. The cut on an edge only depends on
+// its ends, so triangles that share the edge get cut at the same point. If the
+// budget has already run out, triangles that are partly undefined get dropped.
+fn trim_to_domain(
+    triangles: Vec<Triangle3D>,
+    expression: &dyn Expression,
+    context: &GraphContext,
+) -> Result<Vec<Triangle3D>, String> {
+    let GraphContext {
+        var1,
+        var2,
+        var_values,
+        budget,
+    } = *context;
+    let get_key = |point: &Point3D| ((point.0 + 0.0).to_bits(), (point.1 + 0.0).to_bits());
+    let is_defined = |point: &Point3D| point.2.is_finite();
+
+    // The last defined point found on each edge going from a defined corner to
+    // an undefined one, and the first undefined one
+    let mut cuts = HashMap::new();
+    if !budget.is_exhausted() {
+        for Triangle3D(a, b, c) in &triangles {
+            for (start, end) in [(a, b), (b, c), (c, a)] {
+                let (inside, outside) = match (is_defined(start), is_defined(end)) {
+                    (true, false) => (start, end),
+                    (false, true) => (end, start),
+                    _ => continue,
+                };
+                cuts.entry((get_key(inside), get_key(outside)))
+                    .or_insert((*inside, Point2D(outside.0, outside.1)));
+            }
+        }
+    }
+
+    // All the edges take a step at once, so each step is one batch
+    let mut edges: Vec<_> = cuts.keys().copied().collect();
+    edges.sort_unstable();
+    for _ in 0..DOMAIN_EDGE_STEPS {
+        if edges.is_empty() || budget.is_exhausted() {
+            break;
+        }
+        budget.spend(edges.len());
+        let middles: Vec<_> = edges
+            .iter()
+            .map(|edge| {
+                let (inside, outside) = cuts[edge];
+                Point2D((inside.0 + outside.0) / 2.0, (inside.1 + outside.1) / 2.0)
+            })
+            .collect();
+        let xs: Vec<f64> = middles.iter().map(|middle| middle.0).collect();
+        let ys: Vec<f64> = middles.iter().map(|middle| middle.1).collect();
+        let values = expression.evaluate_batch(&BatchValues::new(
+            var_values,
+            vec![(var1, &xs), (var2, &ys)],
+        ))?;
+
+        for ((edge, middle), value) in edges.iter().zip(middles).zip(values) {
+            let cut = cuts.get_mut(edge).unwrap();
+            if value.is_finite() {
+                cut.0 = Point3D(middle.0, middle.1, value);
+            } else {
+                cut.1 = middle;
+            }
+        }
+    }
+
+    let mut trimmed = vec![];
+    for Triangle3D(a, b, c) in triangles {
+        let corners = [a, b, c];
+        if corners.iter().all(is_defined) {
+            trimmed.push(Triangle3D(a, b, c));
+            continue;
+        }
+
+        // Go round the triangle, swapping the undefined corners for the cuts
+        let mut polygon = vec![];
+        for k in 0..3 {
+            let (start, end) = (&corners[k], &corners[(k + 1) % 3]);
+            if is_defined(start) {
+                polygon.push(*start);
+            }
+            let (inside, outside) = match (is_defined(start), is_defined(end)) {
+                (true, false) => (start, end),
+                (false, true) => (end, start),
+                _ => continue,
+            };
+            match cuts.get(&(get_key(inside), get_key(outside))) {
+                Some((cut, _)) => polygon.push(*cut),
+                // The budget ran out before the edges could be cut
+                None => {
+                    polygon.clear();
+                    break;
+                }
+            }
+        }
+        for k in 2..polygon.len() {
+            trimmed.push(Triangle3D(polygon[0], polygon[k - 1], polygon[k]));
+        }
+    }
+    Ok(trimmed)
+}
+
+// Clips the triangle to the window, splitting it exactly where it crosses the
+// window's faces. The pieces keep the triangle's winding.
+fn clip_triangle_3d(triangle: Triangle3D, window: &GraphBox3D) -> Vec<Triangle3D> {
+    // Each face is the axis it's across, where it is on that axis, and which
+    // way is inside
+    let faces = [
+        (0, window.x_min, 1.0),
+        (0, window.x_max, -1.0),
+        (1, window.y_min, 1.0),
+        (1, window.y_max, -1.0),
+        (2, window.z_min, 1.0),
+        (2, window.z_max, -1.0),
+    ];
+
+    let mut polygon: Vec<[f64; 3]> = triangle
+        .into_iter()
+        .map(|point| [point.0, point.1, point.2])
+        .collect();
+    for (axis, position, side) in faces {
+        let distance = |point: &[f64; 3]| side * (point[axis] - position);
+        if polygon.iter().all(|point| distance(point) >= 0.0) {
+            continue;
+        }
+
+        let mut clipped = vec![];
+        for k in 0..polygon.len() {
+            let (start, end) = (polygon[k], polygon[(k + 1) % polygon.len()]);
+            if distance(&start) >= 0.0 {
+                clipped.push(start);
+            }
+            // Points right on the face count as inside, and don't need a
+            // crossing. Always going from the inside end means neighbouring
+            // triangles split their shared edge at exactly the same point.
+            let (inside, outside) = if distance(&start) > 0.0 && distance(&end) < 0.0 {
+                (start, end)
+            } else if distance(&start) < 0.0 && distance(&end) > 0.0 {
+                (end, start)
+            } else {
+                continue;
+            };
+            let t = distance(&inside) / (distance(&inside) - distance(&outside));
+            let mut crossing = [0.0; 3];
+            for i in 0..3 {
+                crossing[i] = inside[i] + t * (outside[i] - inside[i]);
+            }
+            crossing[axis] = position;
+            clipped.push(crossing);
+        }
+        polygon = clipped;
+        if polygon.len() < 3 {
+            return vec![];
+        }
+    }
+
+    let to_point = |[x, y, z]: [f64; 3]| Point3D(x, y, z);
+    (2..polygon.len())
+        .map(|k| {
+            Triangle3D(
+                to_point(polygon[0]),
+                to_point(polygon[k - 1]),
+                to_point(polygon[k]),
+            )
+        })
+        .collect()
+}
+
+// Evaluates the points that haven't been already, in batches (spread over
+// threads with the parallel feature on). Batches after the budget runs out
+// are skipped, so those points are left out of the values.
+fn sample_surface(
+    expression: &dyn Expression,
+    context: &GraphContext,
+    to_point: &(impl Fn((i64, i64)) -> Point2D + Sync),
+    mut keys: Vec<(i64, i64)>,
+    values: &mut HashMap<(i64, i64), f64>,
+) -> Result<(), String> {
+    let GraphContext {
+        var1,
+        var2,
+        var_values,
+        budget,
+    } = *context;
+    keys.retain(|key| !values.contains_key(key));
+    keys.sort_unstable();
+    keys.dedup();
+
+    let batches: Vec<_> = keys.chunks(SURFACE_BATCH_SIZE).collect();
+    let results = map_range(batches.len(), |i| -> Result<Option<Vec<f64>>, String> {
+        if budget.is_exhausted() {
+            return Ok(None);
+        }
+        let (xs, ys): (Vec<f64>, Vec<f64>) = batches[i]
+            .iter()
+            .map(|&key| {
+                let Point2D(x, y) = to_point(key);
+                (x, y)
+            })
+            .unzip();
+        let values = expression.evaluate_batch(&BatchValues::new(
+            var_values,
+            vec![(var1, &xs), (var2, &ys)],
+        ))?;
+        budget.spend(batches[i].len());
+        Ok(Some(values))
+    });
+
+    for (batch, result) in batches.iter().zip(results) {
+        if let Some(batch_values) = result? {
+            values.extend(batch.iter().copied().zip(batch_values));
+        }
+    }
+    Ok(())
+}
+
+// How far the values in the middle of the cell and its edges are from what the
+// corners predict. Cells where the function is only defined at some of those
+// points are on the edge of its domain, and always worth splitting.
+fn get_surface_cell_error(
+    cell: &SurfaceCell,
+    values: &HashMap<(i64, i64), f64>,
+    z_min: f64,
+    z_max: f64,
+) -> f64 {
+    let get_value = |key| values.get(&key).copied().unwrap_or(f64::NAN);
+    let corners = cell.get_corners().map(get_value);
+    let midpoints = cell.get_midpoints().map(get_value);
+
+    let finite_count = corners
+        .iter()
+        .chain(&midpoints)
+        .filter(|value| value.is_finite())
+        .count();
+    if finite_count == 0 {
+        return 0.0;
+    }
+    if finite_count < corners.len() + midpoints.len() {
+        return f64::INFINITY;
+    }
+
+    // Only the part inside the window gets drawn
+    let clamp = |value: f64| value.max(z_min).min(z_max);
+    let [a, b, c, d] = corners.map(clamp);
+    let predicted = [
+        (a + b + c + d) / 4.0,
+        (a + b) / 2.0,
+        (b + d) / 2.0,
+        (c + d) / 2.0,
+        (a + c) / 2.0,
+    ];
+    midpoints
+        .map(clamp)
+        .iter()
+        .zip(predicted)
+        .fold(0.0, |error, (value, predicted)| {
+            error.max((value - predicted).abs())
+        })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LabeledContour2D {
+    pub contour: Contour2D,
+    pub closed: bool,
+    // Where to put the level's label (halfway along the contour)
+    pub label_anchor: Point2D,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LevelSet2D {
+    pub level: f64,
+    pub contours: Vec<LabeledContour2D>,
+}
+
+// Draws the contours where the expression equals each of the levels, like the
+// lines on a topographic map.
+pub fn graph_level_sets_2d(
+    context: &GraphContext,
+    window: &GraphBox,
+    expression: &dyn Expression,
+    levels: &[f64],
+    depth: i64,
+    search_depth: i64,
+    refinement: &EdgeRefinement,
+) -> Result<Vec<LevelSet2D>, String> {
+    let GraphContext {
+        var1,
+        var2,
+        var_values,
+        budget,
+    } = *context;
+    check_variables_2d(expression, var1, var2, var_values)?;
+
+    // Every level looks at the same corners, so they only get evaluated once
+    let samples = SampleCache::for_area(window);
+    let evaluate = get_evaluator_2d(expression, var1, var2, var_values);
+    sample_tree_corners_2d(expression, context, window, search_depth, &samples)?;
+    let f = |p: Point2D| samples.get_or_evaluate(p, &evaluate);
+    let evaluate_gradient = get_gradient_evaluator_2d(expression, var1, var2, var_values);
+    let evaluate_gradient = get_cheating_gradient(&evaluate_gradient);
+    let gradients = SampleCache::<Vec2D>::for_area(window);
+    let df = |p: Point2D| gradients.get_or_evaluate(p, &evaluate_gradient);
+
+    let exclusions = expression.get_real_domain().get_exclusions();
+    let exclusions: Vec<_> = exclusions
+        .iter()
+        .map(|exclusion| get_evaluator_2d(&**exclusion, var1, var2, var_values))
+        .collect();
+    let exclusions: Vec<&dyn Fn(Point2D) -> f64> = exclusions
+        .iter()
+        .map(|exclusion| exclusion as &dyn Fn(Point2D) -> f64)
+        .collect();
+
+    // One tree covers every level, and each level gets stitched from its own
+    // copy of it
+    let tree = build_tree_for_levels(depth, search_depth, window, &f, &df, levels, budget);
+    let mut level_sets = vec![];
+    for &level in levels {
+        let f_level = |p: Point2D| f(p) - level;
+        let mut tree = tree.clone();
+        stitch_tree(&mut tree, window, &f_level, &df, &exclusions, refinement);
+
+        let contours = get_contours_2d(&tree, &df)
+            .into_iter()
+            .map(|contour| LabeledContour2D {
+                label_anchor: get_label_anchor(&contour.points),
+                contour: contour.points,
+                closed: contour.closed,
+            })
+            .collect();
+        level_sets.push(LevelSet2D { level, contours });
+    }
+
+    Ok(level_sets)
+}
+
+// Picks count evenly spaced levels strictly between min and max
+pub fn get_evenly_spaced_levels(min: f64, max: f64, count: usize) -> Vec<f64> {
+    (1..=count)
+        .map(|i| min + (max - min) * i as f64 / (count + 1) as f64)
+        .collect()
+}
+
+// The point halfway along the contour (by length)
+fn get_label_anchor(contour: &Contour2D) -> Point2D {
+    let distance = |a: &Point2D, b: &Point2D| ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt();
+
+    let length: f64 = contour.windows(2).map(|w| distance(&w[0], &w[1])).sum();
+    let mut remaining = length / 2.0;
+    for w in contour.windows(2) {
+        let segment_length = distance(&w[0], &w[1]);
+        if segment_length >= remaining && segment_length > 0.0 {
+            let t = remaining / segment_length;
+            return Point2D(
+                w[0].0 + t * (w[1].0 - w[0].0),
+                w[0].1 + t * (w[1].1 - w[0].1),
+            );
+        }
+        remaining -= segment_length;
+    }
+
+    contour[0]
+}
+
+// Don't sample more than a 2^MAX_SAMPLED_CORNER_LEVELS by
+// 2^MAX_SAMPLED_CORNER_LEVELS grid of corners up front
+const MAX_SAMPLED_CORNER_LEVELS: i64 = 8;
+
+// build_tree always splits the cells down to the search depth, so the corners
+// of those cells get sampled no matter what, and can be evaluated in one batch
+// up front. The coordinates are halved the same way as GraphBox::get_quadrant,
+// so they match the corners build_tree asks for exactly. Corners that are
+// already in the sample cache get left out.
+pub(crate) fn sample_tree_corners_2d(
+    expression: &dyn Expression,
+    context: &GraphContext,
+    area: &GraphBox,
+    search_depth: i64,
+    samples: &SampleCache,
+) -> Result<(), String> {
+    let GraphContext {
+        var1,
+        var2,
+        var_values,
+        budget,
+    } = *context;
+    fn subdivide(min: f64, max: f64, levels: i64) -> Vec<f64> {
+        let mut coordinates = vec![min, max];
+        for _ in 0..levels {
+            let mut halved = Vec::with_capacity(2 * coordinates.len() - 1);
+            for pair in coordinates.windows(2) {
+                halved.push(pair[0]);
+                halved.push((pair[0] + pair[1]) / 2.0);
+            }
+            halved.push(max);
+            coordinates = halved;
+        }
+        coordinates
+    }
+
+    let levels = search_depth.clamp(0, MAX_SAMPLED_CORNER_LEVELS);
+    let x_coordinates = subdivide(area.x_min, area.x_max, levels);
+    let y_coordinates = subdivide(area.y_min, area.y_max, levels);
+
+    let mut xs = Vec::with_capacity(x_coordinates.len() * y_coordinates.len());
+    let mut ys = Vec::with_capacity(x_coordinates.len() * y_coordinates.len());
+    for x in &x_coordinates {
+        for y in &y_coordinates {
+            if !samples.contains(Point2D(*x, *y)) {
+                xs.push(*x);
+                ys.push(*y);
+            }
+        }
+    }
+
+    let values = expression.evaluate_batch(&BatchValues::new(
+        var_values,
+        vec![(var1, &xs), (var2, &ys)],
+    ))?;
+    budget.spend(xs.len());
+    samples.insert(
+        xs.iter()
+            .zip(&ys)
+            .zip(values)
+            .map(|((x, y), value)| (Point2D(*x, *y), value)),
+    );
+    Ok(())
+}
+
+pub(crate) fn build_tree(
+    depth: i64,
+    search_depth: i64,
+    area: &GraphBox,
+    f: &(impl Fn(Point2D) -> f64 + Sync),
+    df: &(impl Fn(Point2D) -> Vec2D + Sync),
+    budget: &EvaluationBudget,
+) -> QuadTreeNode {
+    build_tree_for_levels(depth, search_depth, area, f, df, &[0.0], budget)
+}
+
+// Builds one tree that's interesting wherever f crosses any of the levels, so
+// every level can be stitched from the same tree
+pub(crate) fn build_tree_for_levels(
+    depth: i64,
+    search_depth: i64,
+    area: &GraphBox,
+    f: &(impl Fn(Point2D) -> f64 + Sync),
+    df: &(impl Fn(Point2D) -> Vec2D + Sync),
+    levels: &[f64],
+    budget: &EvaluationBudget,
+) -> QuadTreeNode {
+    let vertex_values = [
+        f(Point2D(area.x_min, area.y_min)),
+        f(Point2D(area.x_max, area.y_min)),
+        f(Point2D(area.x_min, area.y_max)),
+        f(Point2D(area.x_max, area.y_max)),
+    ];
+
+    // Once the budget runs out, treat every cell like it's at the bottom
+    let out_of_budget = budget.is_exhausted();
+
+    if search_depth <= 0 || out_of_budget {
+        // If we're below the search depth, check the vertex values
+        // and stop if they look boring.
+        if let Some(node) = get_boring_node(area, &vertex_values, levels, f, df) {
+            return node;
+        }
+
+        // If we reach this point, the node looks interesting. But if we've hit the bottom
+        // of the tree, we have to stop anyway.
+        // The edge points and vertices get filled in by stitch_tree once we
+        // know the neighbouring cells.
+        if depth <= 0 || out_of_budget {
+            return QuadTreeNode::Leaf(QuadTreeLeafNode {
+                pieces: vec![],
+                pole_points: vec![],
+            });
+        }
+    }
+
+    // Either this node looks interesting or we haven't searched enough yet.
+    // Let's make this a root node and keep going.
+    // The quadrants get built on separate threads with the parallel feature on
+    let build_quadrant = |i| {
+        build_tree_for_levels(
             depth - 1,
             search_depth - 1,
-            &area.get_quadrant(3),
+            &area.get_quadrant(i),
             f,
             df,
-            var1,
-            var2,
-        ),
-    ];
+            levels,
+            budget,
+        )
+    };
+    let ((child0, child1), (child2, child3)) = join(
+        || join(|| build_quadrant(0), || build_quadrant(1)),
+        || join(|| build_quadrant(2), || build_quadrant(3)),
+    );
+    let children = [child0, child1, child2, child3];
 
     // If the children are all boring, then we can collapse this into a boring node without consequence
     if children.iter().all(|child| match child {
@@ -488,112 +1733,1042 @@ fn build_tree(
         return QuadTreeNode::Positive;
     }
 
-    if children.iter().all(|child| match child {
-        QuadTreeNode::Negative => true,
-        _ => false,
-    }) {
-        return QuadTreeNode::Negative;
+    if children.iter().all(|child| match child {
+        QuadTreeNode::Negative => true,
+        _ => false,
+    }) {
+        return QuadTreeNode::Negative;
+    }
+
+    return QuadTreeNode::Root(Box::new(QuadTreeRootNode { children }));
+}
+
+// Lets the tree get deeper where the curve bends and stay shallow where it's
+// straight
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveRefinement {
+    // The most leaves the tree can end up with
+    pub max_leaves: usize,
+    // How far the curve can stray from a straight line through a leaf before
+    // it gets split, as a fraction of the window's diagonal
+    pub tolerance: f64,
+}
+
+impl AdaptiveRefinement {
+    pub fn new(max_leaves: usize, tolerance: f64) -> Self {
+        AdaptiveRefinement {
+            max_leaves,
+            tolerance,
+        }
+    }
+}
+
+impl Default for AdaptiveRefinement {
+    fn default() -> Self {
+        AdaptiveRefinement::new(4000, 0.001)
+    }
+}
+
+// Leaves are found by the quadrants to go through from the top of the tree
+pub(crate) fn collect_leaves(
+    node: &QuadTreeNode,
+    area: &GraphBox,
+    path: &mut Vec<u64>,
+    leaves: &mut Vec<(GraphBox, Vec<u64>)>,
+) {
+    match node {
+        QuadTreeNode::Root(root) => {
+            for (i, child) in root.children.iter().enumerate() {
+                path.push(i as u64);
+                collect_leaves(child, &area.get_quadrant(i as u64), path, leaves);
+                path.pop();
+            }
+        }
+        QuadTreeNode::Leaf(_) => leaves.push((area.clone(), path.clone())),
+        _ => {}
+    }
+}
+
+pub(crate) fn node_at<'a>(node: &'a mut QuadTreeNode, path: &[u64]) -> &'a mut QuadTreeNode {
+    match (path.split_first(), node) {
+        (Some((&i, rest)), QuadTreeNode::Root(root)) => {
+            node_at(&mut root.children[i as usize], rest)
+        }
+        (_, node) => node,
+    }
+}
+
+// Splits the leaves of the tree, worst first, until they're all within the
+// tolerance, they've been split max_depth more times, or we run out of
+// leaves.
+fn refine_tree(
+    tree: &mut QuadTreeNode,
+    window: &GraphBox,
+    max_depth: i64,
+    adaptive: &AdaptiveRefinement,
+    f: &(impl Fn(Point2D) -> f64 + Sync),
+    df: &(impl Fn(Point2D) -> Vec2D + Sync),
+    budget: &EvaluationBudget,
+) {
+    let diagonal =
+        ((window.x_max - window.x_min).powi(2) + (window.y_max - window.y_min).powi(2)).sqrt();
+    let tolerance = adaptive.tolerance * diagonal;
+
+    let mut leaves = vec![];
+    collect_leaves(tree, window, &mut vec![], &mut leaves);
+    let mut leaf_count = leaves.len();
+    let start_depth = leaves.first().map_or(0, |(_, path)| path.len());
+
+    // The heap is ordered by the bits of the error, which sort the same way
+    // as the (non-negative) errors themselves
+    let mut heap = std::collections::BinaryHeap::new();
+    for (i, (area, _)) in leaves.iter().enumerate() {
+        heap.push((get_cell_error(area, f, df).to_bits(), i));
+    }
+
+    while let Some((error, i)) = heap.pop() {
+        let (area, path) = leaves[i].clone();
+        if f64::from_bits(error) <= tolerance
+            || leaf_count + 3 > adaptive.max_leaves
+            || budget.is_exhausted()
+        {
+            break;
+        }
+        if (path.len() - start_depth) as i64 >= max_depth {
+            continue;
+        }
+
+        let node = node_at(tree, &path);
+        *node = build_tree(1, 1, &area, f, df, budget);
+
+        let mut new_leaves = vec![];
+        collect_leaves(node, &area, &mut path.clone(), &mut new_leaves);
+        leaf_count = leaf_count + new_leaves.len() - 1;
+        for (area, path) in new_leaves {
+            heap.push((get_cell_error(&area, f, df).to_bits(), leaves.len()));
+            leaves.push((area, path));
+        }
+    }
+}
+
+// Roughly how far the curve strays from a straight line through the area:
+// how far the value in the middle is from what the corners predict, turned
+// into a distance using the gradient.
+fn get_cell_error(
+    area: &GraphBox,
+    f: &impl Fn(Point2D) -> f64,
+    df: &impl Fn(Point2D) -> Vec2D,
+) -> f64 {
+    let middle = Point2D(
+        (area.x_min + area.x_max) / 2.0,
+        (area.y_min + area.y_max) / 2.0,
+    );
+    let predicted = (f(Point2D(area.x_min, area.y_min))
+        + f(Point2D(area.x_max, area.y_min))
+        + f(Point2D(area.x_min, area.y_max))
+        + f(Point2D(area.x_max, area.y_max)))
+        / 4.0;
+    let gradient = df(middle);
+
+    let error =
+        (f(middle) - predicted).abs() / (gradient.0 * gradient.0 + gradient.1 * gradient.1).sqrt();
+    if error.is_finite() {
+        error
+    } else {
+        0.0
+    }
+}
+
+// Whether the function could touch zero inside the area without changing sign
+// (like (y - x)^2 = 0). That needs the gradient to turn around somewhere in the
+// area, and the function to be small enough that it could get to zero at the
+// steepest slope we see.
+// A cell is boring if its corners all sit between the same two levels and f
+// can't reach a level in between. Neighbouring bands get opposite signs so
+// boring cells on either side of a level never collapse into one.
+fn get_boring_node(
+    area: &GraphBox,
+    vertex_values: &[f64; 4],
+    levels: &[f64],
+    f: &impl Fn(Point2D) -> f64,
+    df: &impl Fn(Point2D) -> Vec2D,
+) -> Option<QuadTreeNode> {
+    if levels
+        .iter()
+        .any(|level| vertex_values.iter().all(|value| value == level))
+    {
+        return Some(QuadTreeNode::Zero);
+    }
+    if vertex_values.iter().any(|value| value.is_nan()) {
+        return None;
+    }
+
+    let band = |value: &f64| levels.iter().filter(|level| value >= level).count();
+    let first_band = band(&vertex_values[0]);
+    if vertex_values.iter().any(|value| band(value) != first_band) {
+        return None;
+    }
+
+    // The centre is a corner of all four quadrants, so it's already sampled if
+    // the cell gets split. It catches dips the corners can't see.
+    let center_value = f(Point2D(
+        (area.x_min + area.x_max) / 2.0,
+        (area.y_min + area.y_max) / 2.0,
+    ));
+    if center_value.is_nan() || band(&center_value) != first_band {
+        return None;
+    }
+
+    let distance = |value: f64| {
+        levels
+            .iter()
+            .fold(f64::INFINITY, |acc, level| acc.min((value - level).abs()))
+    };
+    let distances = vertex_values.map(distance);
+
+    // How far the centre is from the average of the corners. A bilinear f
+    // comes closest to a level at a corner, so the gradients only need looking
+    // at when f bends about as much as it's away from the nearest level.
+    let bend = (center_value - vertex_values.iter().sum::<f64>() / 4.0).abs();
+    let nearest = distances.iter().fold(f64::INFINITY, |acc, d| acc.min(*d));
+    if 2.0 * bend >= nearest && may_touch_zero(area, &distances, df) {
+        return None;
+    }
+
+    Some(if first_band % 2 == 0 {
+        QuadTreeNode::Negative
+    } else {
+        QuadTreeNode::Positive
+    })
+}
+
+fn may_touch_zero(
+    area: &GraphBox,
+    vertex_values: &[f64; 4],
+    df: &impl Fn(Point2D) -> Vec2D,
+) -> bool {
+    let gradients = [
+        df(Point2D(area.x_min, area.y_min)),
+        df(Point2D(area.x_max, area.y_min)),
+        df(Point2D(area.x_min, area.y_max)),
+        df(Point2D(area.x_max, area.y_max)),
+    ];
+
+    let turns_x = gradients.iter().any(|g| g.0 < 0.0) && gradients.iter().any(|g| g.0 > 0.0);
+    let turns_y = gradients.iter().any(|g| g.1 < 0.0) && gradients.iter().any(|g| g.1 > 0.0);
+    if !turns_x && !turns_y {
+        return false;
+    }
+
+    let smallest_value = vertex_values
+        .iter()
+        .fold(f64::INFINITY, |acc, value| acc.min(value.abs()));
+    let steepest_slope = gradients
+        .iter()
+        .map(|g| (g.0 * g.0 + g.1 * g.1).sqrt())
+        .fold(0.0, f64::max);
+    let diagonal = ((area.x_max - area.x_min).powi(2) + (area.y_max - area.y_min).powi(2)).sqrt();
+
+    smallest_value <= steepest_slope * diagonal
+}
+
+pub fn get_cheating_gradient<'a>(
+    df: &'a (dyn Fn(Point2D) -> Vec2D + Sync),
+) -> Box<dyn Fn(Point2D) -> Vec2D + Sync + 'a> {
+    fn is_valid_result(result: &Vec2D) -> bool {
+        result.0.is_finite() && result.1.is_finite()
+    }
+
+    Box::new(move |Point2D(x, y)| {
+        let epsilon = 0.00001;
+        let mut result = df(Point2D(x, y));
+        if !is_valid_result(&result) {
+            result = df(Point2D(x + epsilon, y + epsilon));
+            if !is_valid_result(&result) {
+                result = df(Point2D(x - epsilon, y - epsilon));
+                if !is_valid_result(&result) {
+                    result = df(Point2D(x + epsilon, y - epsilon));
+                    if !is_valid_result(&result) {
+                        result = df(Point2D(x - epsilon, y + epsilon));
+                        if !is_valid_result(&result) {
+                            result = df(Point2D(x + epsilon, y));
+                            if !is_valid_result(&result) {
+                                result = df(Point2D(x - epsilon, y));
+                                if !is_valid_result(&result) {
+                                    result = df(Point2D(x, y + epsilon));
+                                    if !is_valid_result(&result) {
+                                        result = df(Point2D(x, y - epsilon));
+                                        if !is_valid_result(&result) {
+                                            result = Vec2D(0.0, 0.0);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    })
+}
+
+pub(crate) fn get_contours_2d(
+    tree_node: &QuadTreeNode,
+    df: &dyn Fn(Point2D) -> Vec2D,
+) -> Vec<Polyline2D> {
+    fn get_segments(tree_node: &QuadTreeNode) -> Vec<Segment2D> {
+        match tree_node {
+            QuadTreeNode::Root(root) => {
+                let mut segments = vec![];
+                for child in &root.children {
+                    segments.append(&mut get_segments(child));
+                }
+                segments
+            }
+            QuadTreeNode::Leaf(leaf) => {
+                let mut segments = vec![];
+                for (edge_points, vertex) in &leaf.pieces {
+                    for point in edge_points {
+                        segments.push(Segment2D(*point, *vertex));
+                    }
+                }
+                segments
+            }
+            _ => vec![],
+        }
+    }
+
+    let segments = get_segments(tree_node);
+    let contours = segments_to_contours(&segments, df);
+    contours
+}
+
+// Joins up the pole points in each leaf the same way get_contours_2d joins the
+// edge points
+fn get_asymptotes_2d(tree_node: &QuadTreeNode, df: &dyn Fn(Point2D) -> Vec2D) -> Vec<Polyline2D> {
+    fn get_segments(tree_node: &QuadTreeNode) -> Vec<Segment2D> {
+        match tree_node {
+            QuadTreeNode::Root(root) => {
+                let mut segments = vec![];
+                for child in &root.children {
+                    segments.append(&mut get_segments(child));
+                }
+                segments
+            }
+            QuadTreeNode::Leaf(leaf) if !leaf.pole_points.is_empty() => {
+                let mut middle = Point2D(0.0, 0.0);
+                for point in &leaf.pole_points {
+                    middle += *point;
+                }
+                middle.0 /= leaf.pole_points.len() as f64;
+                middle.1 /= leaf.pole_points.len() as f64;
+
+                let mut segments = vec![];
+                for point in &leaf.pole_points {
+                    segments.push(Segment2D(*point, middle));
+                }
+                segments
+            }
+            _ => vec![],
+        }
+    }
+
+    let segments = get_segments(tree_node);
+    segments_to_contours(&segments, df)
+}
+
+// Joins segments into maximal polylines, with each one oriented so the
+// positive side of the function (according to df) is on its left.
+fn segments_to_contours(segments: &[Segment2D], df: &dyn Fn(Point2D) -> Vec2D) -> Vec<Polyline2D> {
+    // Adding 0.0 turns -0.0 into 0.0 so they hash the same
+    fn point_key(point: &Point2D) -> (u64, u64) {
+        ((point.0 + 0.0).to_bits(), (point.1 + 0.0).to_bits())
+    }
+
+    let segments: Vec<&Segment2D> = segments
+        .iter()
+        .filter(|segment| segment.0 != segment.1)
+        .collect();
+
+    let mut segments_at_point: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for (i, segment) in segments.iter().enumerate() {
+        segments_at_point
+            .entry(point_key(&segment.0))
+            .or_default()
+            .push(i);
+        segments_at_point
+            .entry(point_key(&segment.1))
+            .or_default()
+            .push(i);
+    }
+
+    let mut used = vec![false; segments.len()];
+
+    // Keeps taking unused segments from the end of the polyline until it
+    // runs out
+    let extend = |points: &mut Contour2D, used: &mut Vec<bool>| loop {
+        let end = *points.last().unwrap();
+        let next = segments_at_point[&point_key(&end)]
+            .iter()
+            .find(|&&i| !used[i])
+            .copied();
+        match next {
+            Some(i) => {
+                used[i] = true;
+                let segment = segments[i];
+                points.push(if point_key(&segment.0) == point_key(&end) {
+                    segment.1
+                } else {
+                    segment.0
+                });
+            }
+            None => break,
+        }
+    };
+
+    let mut contours = vec![];
+
+    // Start at the loose ends and junctions first so open curves aren't
+    // split in the middle, then everything left over is a loop
+    for start_at_ends in [true, false] {
+        for i in 0..segments.len() {
+            if used[i] {
+                continue;
+            }
+            let segment = segments[i];
+            let (start, next) =
+                if !start_at_ends || segments_at_point[&point_key(&segment.0)].len() != 2 {
+                    (segment.0, segment.1)
+                } else if segments_at_point[&point_key(&segment.1)].len() != 2 {
+                    (segment.1, segment.0)
+                } else {
+                    continue;
+                };
+
+            used[i] = true;
+            let mut points = vec![start, next];
+            extend(&mut points, &mut used);
+
+            let closed = points.len() > 3
+                && point_key(points.first().unwrap()) == point_key(points.last().unwrap());
+            contours.push(Polyline2D { points, closed });
+        }
+    }
+
+    for contour in &mut contours {
+        let left_gradient: f64 = contour
+            .points
+            .windows(2)
+            .map(|w| {
+                let middle = Point2D((w[0].0 + w[1].0) / 2.0, (w[0].1 + w[1].1) / 2.0);
+                let left = Vec2D(w[0].1 - w[1].1, w[1].0 - w[0].0);
+                let gradient = df(middle);
+                if gradient.0.is_finite() && gradient.1.is_finite() {
+                    gradient.dot(&left)
+                } else {
+                    0.0
+                }
+            })
+            .sum();
+        if left_gradient < 0.0 {
+            contour.points.reverse();
+        }
+    }
+
+    contours
+}
+
+// Fills in the edge points and vertices of the tree's leaves so that
+// neighbouring leaves share exactly the same edge points
+pub(crate) fn stitch_tree(
+    tree: &mut QuadTreeNode,
+    window: &GraphBox,
+    f: &dyn Fn(Point2D) -> f64,
+    df: &dyn Fn(Point2D) -> Vec2D,
+    exclusions: &[&dyn Fn(Point2D) -> f64],
+    refinement: &EdgeRefinement,
+) {
+    fn collect_cells(node: &QuadTreeNode, area: &GraphBox, cells: &mut Vec<(GraphBox, bool)>) {
+        match node {
+            QuadTreeNode::Root(root) => {
+                for (i, child) in root.children.iter().enumerate() {
+                    collect_cells(child, &area.get_quadrant(i as u64), cells);
+                }
+            }
+            QuadTreeNode::Leaf(_) => cells.push((area.clone(), true)),
+            QuadTreeNode::Negative | QuadTreeNode::Positive => cells.push((area.clone(), false)),
+            QuadTreeNode::Zero => {}
+        }
+    }
+
+    // Walks the tree in the same order as collect_cells
+    fn update_cells(
+        node: &mut QuadTreeNode,
+        leaves: &mut impl Iterator<Item = Option<QuadTreeLeafNode>>,
+    ) {
+        match node {
+            QuadTreeNode::Root(root) => {
+                for child in root.children.iter_mut() {
+                    update_cells(child, leaves);
+                }
+            }
+            QuadTreeNode::Zero => {}
+            _ => {
+                if let Some(leaf) = leaves.next().unwrap() {
+                    *node = QuadTreeNode::Leaf(leaf);
+                }
+            }
+        }
+    }
+
+    let mut cells = vec![];
+    collect_cells(tree, window, &mut cells);
+    let leaves = stitch_cells(&mut cells, window, f, df, exclusions, refinement);
+    update_cells(tree, &mut leaves.into_iter());
+}
+
+// Finds the edge points and vertices of every leaf cell (the cells marked
+// true).
+//
+// Each edge point is found once on the smallest piece of edge between two
+// cell corners, so two neighbouring cells always agree on it, even when one is
+// bigger than the other (a T-junction). If a contour crosses into a cell that
+// looked boring from its corners, that cell becomes a leaf too, so the contour
+// doesn't just stop.
+//
+// Sign changes across poles (like tan(x) = y at x = pi/2) are kept apart from
+// the edge points, so they don't get drawn as part of the curve.
+fn stitch_cells(
+    cells: &mut [(GraphBox, bool)],
+    window: &GraphBox,
+    f: &dyn Fn(Point2D) -> f64,
+    df: &dyn Fn(Point2D) -> Vec2D,
+    exclusions: &[&dyn Fn(Point2D) -> f64],
+    refinement: &EdgeRefinement,
+) -> Vec<Option<QuadTreeLeafNode>> {
+    // Adding 0.0 turns -0.0 into 0.0 so they hash the same
+    fn point_key(point: &Point2D) -> (u64, u64) {
+        ((point.0 + 0.0).to_bits(), (point.1 + 0.0).to_bits())
+    }
+
+    // The horizontal (true) or vertical (false) lines the edges of the area
+    // are on, with where each edge starts and ends along its line
+    fn get_edge_lines(area: &GraphBox) -> [((bool, u64), f64, f64); 4] {
+        [
+            ((true, (area.y_min + 0.0).to_bits()), area.x_min, area.x_max),
+            ((true, (area.y_max + 0.0).to_bits()), area.x_min, area.x_max),
+            (
+                (false, (area.x_min + 0.0).to_bits()),
+                area.y_min,
+                area.y_max,
+            ),
+            (
+                (false, (area.x_max + 0.0).to_bits()),
+                area.y_min,
+                area.y_max,
+            ),
+        ]
+    }
+
+    // Keeps each line's splits sorted
+    fn add_splits(splits: &mut HashMap<(bool, u64), Vec<f64>>, area: &GraphBox) {
+        for (line, start, end) in get_edge_lines(area) {
+            let line = splits.entry(line).or_default();
+            for t in [start, end] {
+                if let Err(i) = line.binary_search_by(|u| u.total_cmp(&t)) {
+                    line.insert(i, t);
+                }
+            }
+        }
+    }
+
+    let mut values: HashMap<(u64, u64), f64> = HashMap::new();
+    let mut value_at =
+        |point: Point2D| *values.entry(point_key(&point)).or_insert_with(|| f(point));
+
+    let mut gradients: HashMap<(u64, u64), Vec2D> = HashMap::new();
+    let mut gradient_at = |point: Point2D| {
+        *gradients
+            .entry(point_key(&point))
+            .or_insert_with(|| df(point))
+    };
+
+    // The crossing on each piece of edge, and whether it's a pole
+    let mut crossings: HashMap<EdgeKey, Option<(Point2D, bool)>> = HashMap::new();
+
+    // Every cell with an edge on each line, for finding neighbours
+    let mut cells_on_line: HashMap<(bool, u64), Vec<usize>> = HashMap::new();
+    for (i, (area, _)) in cells.iter().enumerate() {
+        for (line, _, _) in get_edge_lines(area) {
+            cells_on_line.entry(line).or_default().push(i);
+        }
+    }
+
+    // Every leaf corner along each line splits the edges on that line
+    let mut splits: HashMap<(bool, u64), Vec<f64>> = HashMap::new();
+    for (area, _) in cells.iter().filter(|(_, leaf)| *leaf) {
+        add_splits(&mut splits, area);
     }
 
-    return QuadTreeNode::Root(Box::new(QuadTreeRootNode { children }));
-}
+    // How many leaves found a crossing on each piece of edge, and which pieces
+    // each leaf found them on
+    let mut crossing_counts: HashMap<EdgeKey, usize> = HashMap::new();
+    let mut found: Vec<Vec<EdgeKey>> = vec![vec![]; cells.len()];
+    let mut all_edge_points = vec![None; cells.len()];
+
+    // Starting with every leaf, find the edge points of the cells that need
+    // them, then promote any boring cells they lead into. Only the promoted
+    // cells and the leaves they split the edges of need looking at again.
+    let mut worklist: Vec<usize> = (0..cells.len()).filter(|&i| cells[i].1).collect();
+    while !worklist.is_empty() {
+        let mut changed = vec![];
+        for &cell in &worklist {
+            for key in found[cell].drain(..) {
+                *crossing_counts.get_mut(&key).unwrap() -= 1;
+                changed.push(key);
+            }
 
-pub fn get_cheating_gradient<'a>(
-    df: &'a dyn Fn(Point2D) -> Vec2D,
-) -> Box<dyn Fn(Point2D) -> Vec2D + 'a> {
-    fn is_valid_result(result: &Vec2D) -> bool {
-        result.0.is_finite() && result.1.is_finite()
-    }
+            let area = &cells[cell].0;
+            // Going counterclockwise from the bottom left corner, with whether
+            // that's towards the end of the edge and how far around we are
+            // at its start
+            let width = area.x_max - area.x_min;
+            let height = area.y_max - area.y_min;
+            let edges = [
+                (true, area.y_min, area.x_min, area.x_max, true, 0.0),
+                (false, area.x_max, area.y_min, area.y_max, true, width),
+                (
+                    true,
+                    area.y_max,
+                    area.x_min,
+                    area.x_max,
+                    false,
+                    width + height,
+                ),
+                (
+                    false,
+                    area.x_min,
+                    area.y_min,
+                    area.y_max,
+                    false,
+                    2.0 * width + height,
+                ),
+            ];
+
+            let mut edge_points: Vec<Point2D> = vec![];
+            // For edge points where the function changes sign, how far around
+            // the cell they are and whether it's positive after them
+            let mut sign_changes: Vec<Option<(f64, bool)>> = vec![];
+            let mut pole_points: Vec<Point2D> = vec![];
+            for (horizontal, fixed, start, end, forwards, distance) in edges {
+                let line = &splits[&(horizontal, (fixed + 0.0).to_bits())];
+                let first = line.partition_point(|t| t < &start);
+                let last = line.partition_point(|t| t <= &end);
+                let to_point = |t: f64| {
+                    if horizontal {
+                        Point2D(t, fixed)
+                    } else {
+                        Point2D(fixed, t)
+                    }
+                };
+
+                for i in first..last {
+                    let a = to_point(line[i]);
+                    let a_val = value_at(a);
+                    if a_val == 0.0 {
+                        edge_points.push(a);
+                        sign_changes.push(None);
+                    }
+                    if i + 1 == last {
+                        continue;
+                    }
 
-    Box::new(move |Point2D(x, y)| {
-        let epsilon = 0.00001;
-        let mut result = df(Point2D(x, y));
-        if !is_valid_result(&result) {
-            result = df(Point2D(x + epsilon, y + epsilon));
-            if !is_valid_result(&result) {
-                result = df(Point2D(x - epsilon, y - epsilon));
-                if !is_valid_result(&result) {
-                    result = df(Point2D(x + epsilon, y - epsilon));
-                    if !is_valid_result(&result) {
-                        result = df(Point2D(x - epsilon, y + epsilon));
-                        if !is_valid_result(&result) {
-                            result = df(Point2D(x + epsilon, y));
-                            if !is_valid_result(&result) {
-                                result = df(Point2D(x - epsilon, y));
-                                if !is_valid_result(&result) {
-                                    result = df(Point2D(x, y + epsilon));
-                                    if !is_valid_result(&result) {
-                                        result = df(Point2D(x, y - epsilon));
-                                        if !is_valid_result(&result) {
-                                            result = Vec2D(0.0, 0.0);
-                                        }
-                                    }
+                    let b = to_point(line[i + 1]);
+                    let b_val = value_at(b);
+                    let key = (point_key(&a), point_key(&b));
+                    let crossing = match crossings.get(&key) {
+                        Some(crossing) => *crossing,
+                        None => {
+                            let crossing = if a_val * b_val < 0.0 {
+                                let crossing =
+                                    find_edge_crossing(f, a, a_val, b, b_val, refinement);
+                                let pole =
+                                    is_pole_crossing(f, exclusions, a, a_val, b, b_val, crossing);
+                                Some((crossing, pole))
+                            } else if a_val * b_val > 0.0 {
+                                find_tangential_crossing(
+                                    f,
+                                    df,
+                                    (a, a_val, gradient_at(a)),
+                                    (b, gradient_at(b)),
+                                    refinement,
+                                )
+                                .map(|crossing| (crossing, false))
+                            } else {
+                                None
+                            };
+                            crossings.insert(key, crossing);
+                            crossing
+                        }
+                    };
+                    match crossing {
+                        Some((crossing, false)) => {
+                            edge_points.push(crossing);
+                            sign_changes.push(if a_val * b_val < 0.0 {
+                                let along = if horizontal { crossing.0 } else { crossing.1 };
+                                if forwards {
+                                    Some((distance + along - start, b_val > 0.0))
+                                } else {
+                                    Some((distance + end - along, a_val > 0.0))
                                 }
-                            }
+                            } else {
+                                None
+                            });
+                            *crossing_counts.entry(key).or_default() += 1;
+                            found[cell].push(key);
                         }
+                        Some((crossing, true)) => pole_points.push(crossing),
+                        None => {}
                     }
                 }
             }
+
+            // Corners get visited by two edges
+            let mut seen = std::collections::HashSet::new();
+            let (edge_points, sign_changes): (Vec<_>, Vec<_>) = edge_points
+                .into_iter()
+                .zip(sign_changes)
+                .filter(|(point, _)| seen.insert(point_key(point)))
+                .unzip();
+
+            let corner_values = [
+                value_at(Point2D(area.x_min, area.y_min)),
+                value_at(Point2D(area.x_max, area.y_min)),
+                value_at(Point2D(area.x_min, area.y_max)),
+                value_at(Point2D(area.x_max, area.y_max)),
+            ];
+            let groups = group_edge_points(&edge_points, &sign_changes, &corner_values);
+
+            changed.extend(found[cell].iter().copied());
+            all_edge_points[cell] = Some((groups, pole_points));
         }
 
-        result
-    })
-}
+        // Edge points inside the window that only one leaf found lead into a
+        // boring cell
+        let mut promoted = vec![];
+        for (a, b) in changed {
+            if crossing_counts[&(a, b)] != 1 {
+                continue;
+            }
+            let a = Point2D(f64::from_bits(a.0), f64::from_bits(a.1));
+            let b = Point2D(f64::from_bits(b.0), f64::from_bits(b.1));
+            let on_window_edge = (a.1 == b.1 && (a.1 == window.y_min || a.1 == window.y_max))
+                || (a.0 == b.0 && (a.0 == window.x_min || a.0 == window.x_max));
+            if on_window_edge {
+                continue;
+            }
 
-fn get_contours_2d(tree_node: &QuadTreeNode) -> Vec<Contour2D> {
-    fn get_segments(tree_node: &QuadTreeNode) -> Vec<Segment2D> {
-        match tree_node {
-            QuadTreeNode::Root(root) => {
-                let mut segments = vec![];
-                for child in &root.children {
-                    segments.append(&mut get_segments(child));
+            let middle = Point2D((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+            let line = if a.1 == b.1 {
+                (true, (a.1 + 0.0).to_bits())
+            } else {
+                (false, (a.0 + 0.0).to_bits())
+            };
+            for &j in cells_on_line.get(&line).into_iter().flatten() {
+                let (area, leaf) = &mut cells[j];
+                if !*leaf
+                    && middle.0 >= area.x_min
+                    && middle.0 <= area.x_max
+                    && middle.1 >= area.y_min
+                    && middle.1 <= area.y_max
+                {
+                    *leaf = true;
+                    promoted.push(j);
                 }
-                segments
             }
-            QuadTreeNode::Leaf(leaf) => {
-                let mut segments = vec![];
-                for point in &leaf.edge_points {
-                    segments.push(Segment2D(point.clone(), leaf.vertex.clone()));
+        }
+
+        let mut next = std::collections::BTreeSet::new();
+        for &j in &promoted {
+            add_splits(&mut splits, &cells[j].0);
+        }
+        for &j in &promoted {
+            next.insert(j);
+            for (line, start, end) in get_edge_lines(&cells[j].0) {
+                for &k in &cells_on_line[&line] {
+                    let (area, leaf) = &cells[k];
+                    let (other_start, other_end) = if line.0 {
+                        (area.x_min, area.x_max)
+                    } else {
+                        (area.y_min, area.y_max)
+                    };
+                    if *leaf && other_start <= end && other_end >= start {
+                        next.insert(k);
+                    }
                 }
-                segments
             }
-            _ => vec![],
         }
+        worklist = next.into_iter().collect();
     }
 
-    let segments = get_segments(tree_node);
-    let contours = segments_to_contours(&segments);
-    contours
+    cells
+        .iter()
+        .zip(all_edge_points)
+        .map(|((area, _), key_points)| {
+            key_points.map(|(groups, pole_points)| QuadTreeLeafNode {
+                pieces: groups
+                    .into_iter()
+                    .map(|edge_points| {
+                        let vertex = get_leaf_vertex(area, &edge_points, df);
+                        (edge_points, vertex)
+                    })
+                    .collect(),
+                pole_points,
+            })
+        })
+        .collect()
+}
+
+// A piece of edge between two cell corners, by the keys of its ends
+type EdgeKey = ((u64, u64), (u64, u64));
+
+// Splits a leaf's edge points into the separate pieces of curve through it.
+// Going around the cell, the sign changes alternate between entering a
+// positive and a negative stretch of the edge, and each piece of curve cuts
+// off one of those stretches. If there's more than one of each, whether the
+// pieces cut off the positive or negative stretches depends on which sign
+// joins up through the middle of the cell, which we guess from the saddle
+// point of the bilinear interpolation of the corners (the asymptotic
+// decider).
+//
+// Edge points that aren't simple sign changes (zero corners and places the
+// curve only touches) can't be sorted out like that, so then everything is
+// one piece.
+fn group_edge_points(
+    edge_points: &[Point2D],
+    sign_changes: &[Option<(f64, bool)>],
+    corner_values: &[f64; 4],
+) -> Vec<Vec<Point2D>> {
+    if edge_points.is_empty() {
+        return vec![];
+    }
+
+    let mut sign_changes: Vec<(f64, bool, Point2D)> = match sign_changes
+        .iter()
+        .zip(edge_points)
+        .map(|(sign_change, point)| {
+            sign_change.map(|(distance, positive)| (distance, positive, *point))
+        })
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(sign_changes) if sign_changes.len() > 2 && sign_changes.len() % 2 == 0 => sign_changes,
+        _ => return vec![edge_points.to_vec()],
+    };
+    sign_changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let alternates = (0..sign_changes.len())
+        .all(|i| sign_changes[i].1 != sign_changes[(i + 1) % sign_changes.len()].1);
+    if !alternates {
+        return vec![edge_points.to_vec()];
+    }
+
+    let [v00, v10, v01, v11] = *corner_values;
+    let denominator = v00 + v11 - v10 - v01;
+    let middle_value = if denominator == 0.0 {
+        (v00 + v10 + v01 + v11) / 4.0
+    } else {
+        (v00 * v11 - v10 * v01) / denominator
+    };
+    let positive_joined = middle_value >= 0.0;
+
+    // Start from a sign change going into a stretch that gets cut off
+    let start = if sign_changes[0].1 != positive_joined {
+        0
+    } else {
+        1
+    };
+    (0..sign_changes.len() / 2)
+        .map(|i| {
+            let first = start + 2 * i;
+            vec![
+                sign_changes[first].2,
+                sign_changes[(first + 1) % sign_changes.len()].2,
+            ]
+        })
+        .collect()
+}
+
+// Whether the sign change between a and b is from a pole rather than a zero.
+// Near a zero the function gets smaller than at either end, but near a pole it
+// blows up. Exclusions from the real domain changing sign between a and b
+// mean there's probably a pole, so then we're stricter.
+fn is_pole_crossing(
+    f: &dyn Fn(Point2D) -> f64,
+    exclusions: &[&dyn Fn(Point2D) -> f64],
+    a: Point2D,
+    a_val: f64,
+    b: Point2D,
+    b_val: f64,
+    crossing: Point2D,
+) -> bool {
+    let value = f(crossing).abs();
+    if !value.is_finite() {
+        return true;
+    }
+
+    let excluded = exclusions
+        .iter()
+        .any(|exclusion| exclusion(a) * exclusion(b) <= 0.0);
+    if excluded {
+        value > a_val.abs().min(b_val.abs())
+    } else {
+        value > a_val.abs().max(b_val.abs())
+    }
 }
 
-fn segments_to_contours(segments: &Vec<Segment2D>) -> Vec<Contour2D> {
-    let mut contours: Vec<Contour2D> = vec![];
-    for segment in segments {
-        let mut done = false;
-        for contour in &mut contours {
-            if contour.last().unwrap() == &segment.0 {
-                contour.push(segment.1.clone());
-                done = true;
-                break;
-            } else if contour.first().unwrap() == &segment.1 {
-                contour.insert(0, segment.0.clone());
-                done = true;
-                break;
-            } else if contour.last().unwrap() == &segment.1 {
-                contour.push(segment.0.clone());
-                done = true;
-                break;
-            } else if contour.first().unwrap() == &segment.0 {
-                contour.insert(0, segment.1.clone());
-                done = true;
-                break;
-            }
-        }
-        if !done {
-            contours.push(vec![segment.0.clone(), segment.1.clone()]);
+// Where the curve touches the edge from a to b without the function changing
+// sign (a double root, like (y - x)^2 = 0). The slope along the edge changes
+// sign there instead, so look for that, and then check the function really is
+// zero there, rather than just small compared to the ends of the edge (like
+// (y - 3.7)^2 + 0.01 = 0, which never touches zero). That needs the minimum
+// found precisely, however coarse the refinement is.
+fn find_tangential_crossing(
+    f: &dyn Fn(Point2D) -> f64,
+    df: &dyn Fn(Point2D) -> Vec2D,
+    (a, a_val, a_gradient): (Point2D, f64, Vec2D),
+    (b, b_gradient): (Point2D, Vec2D),
+    refinement: &EdgeRefinement,
+) -> Option<Point2D> {
+    let direction = Vec2D(b.0 - a.0, b.1 - a.1);
+    let a_slope = a_gradient.dot(&direction);
+    let b_slope = b_gradient.dot(&direction);
+
+    // Going towards zero from a and away from it at b
+    let turns = a_slope * b_slope < 0.0;
+    if !turns || (a_slope < 0.0) != (a_val > 0.0) {
+        return None;
+    }
+
+    let slope = |p: Point2D| df(p).dot(&direction);
+    let precise = EdgeRefinement::new(
+        refinement.max_steps.max(TANGENTIAL_STEPS),
+        refinement.tolerance.min(TANGENTIAL_STEP_TOLERANCE),
+    );
+    let crossing = find_edge_crossing(&slope, a, a_slope, b, b_slope, &precise);
+
+    // How much the function changes along the edge at most, going by the
+    // steepest slope at either end
+    let steepest = (a_gradient.0.hypot(a_gradient.1)).max(b_gradient.0.hypot(b_gradient.1));
+    let change = steepest * direction.0.hypot(direction.1);
+    if f(crossing).abs() <= TANGENTIAL_ZERO_TOLERANCE * change {
+        Some(crossing)
+    } else {
+        None
+    }
+}
+
+// How small a minimum of |f| has to be, compared to how much f changes along
+// the edge, to count as touching zero
+const TANGENTIAL_ZERO_TOLERANCE: f64 = 1e-9;
+
+// The least refinement used to find where the slope along an edge is zero
+const TANGENTIAL_STEPS: u32 = 64;
+const TANGENTIAL_STEP_TOLERANCE: f64 = 1e-12;
+
+// How hard to look for the point where a contour crosses a cell edge
+#[derive(Clone, Copy, Debug)]
+pub struct EdgeRefinement {
+    // Steps of the Illinois method to take on each edge (0 just interpolates
+    // between the corner values)
+    pub max_steps: u32,
+    // Stop once a step moves the crossing by less than this fraction of the
+    // edge's length
+    pub tolerance: f64,
+}
+
+impl EdgeRefinement {
+    pub fn new(max_steps: u32, tolerance: f64) -> Self {
+        EdgeRefinement {
+            max_steps,
+            tolerance,
         }
     }
-    contours
+}
+
+impl Default for EdgeRefinement {
+    fn default() -> Self {
+        EdgeRefinement::new(8, 0.0001)
+    }
+}
+
+// Where the function crosses zero on the edge from start to end, starting from
+// the linear interpolation of the end values and refining it with the
+// Illinois method (regula falsi that halves the value at an end point which
+// keeps getting reused)
+fn find_edge_crossing(
+    f: &dyn Fn(Point2D) -> f64,
+    start: Point2D,
+    start_val: f64,
+    end: Point2D,
+    end_val: f64,
+    refinement: &EdgeRefinement,
+) -> Point2D {
+    let point_at = |t: f64| {
+        Point2D(
+            start.0 + t * (end.0 - start.0),
+            start.1 + t * (end.1 - start.1),
+        )
+    };
+    let interpolate = |a: f64, a_val: f64, b: f64, b_val: f64| {
+        // An infinite end (like 1/x at x = 0) is as close as we can get
+        if !a_val.is_finite() {
+            a
+        } else if !b_val.is_finite() {
+            b
+        } else if a_val == b_val {
+            (a + b) / 2.0
+        } else {
+            (a * b_val - b * a_val) / (b_val - a_val)
+        }
+    };
+
+    // Work in terms of how far along the edge we are
+    let (mut a, mut a_val, mut b, mut b_val) = (0.0, start_val, 1.0, end_val);
+    let mut t = interpolate(a, a_val, b, b_val);
+    let mut last_side = 0;
+    for _ in 0..refinement.max_steps {
+        let t_val = f(point_at(t));
+        if t_val == 0.0 || !t_val.is_finite() {
+            break;
+        }
+
+        if (t_val < 0.0) == (a_val < 0.0) {
+            a = t;
+            a_val = t_val;
+            if last_side == -1 {
+                b_val /= 2.0;
+            }
+            last_side = -1;
+        } else {
+            b = t;
+            b_val = t_val;
+            if last_side == 1 {
+                a_val /= 2.0;
+            }
+            last_side = 1;
+        }
+
+        let next_t = interpolate(a, a_val, b, b_val);
+        let step = (next_t - t).abs();
+        t = next_t;
+        if step <= refinement.tolerance {
+            break;
+        }
+    }
+
+    point_at(t)
 }
 
 pub fn get_leaf_key_points(
@@ -601,15 +2776,6 @@ pub fn get_leaf_key_points(
     f: &dyn Fn(Point2D) -> f64,
     df: &dyn Fn(Point2D) -> Vec2D,
 ) -> (Vec<Point2D>, Point2D) {
-    fn find_zero(start: f64, start_val: f64, end: f64, end_val: f64) -> f64 {
-        let t = if start_val == end_val {
-            0.5
-        } else {
-            -start_val / (end_val - start_val)
-        };
-        start + t * (end - start)
-    }
-
     let corner_values = [
         f(Point2D(area.x_min, area.y_min)),
         f(Point2D(area.x_max, area.y_min)),
@@ -630,43 +2796,45 @@ pub fn get_leaf_key_points(
     if corner_values[3] == 0.0 {
         edge_points.push(Point2D(area.x_max, area.y_max));
     }
-    if corner_values[0] * corner_values[1] < 0.0 {
-        edge_points.push(Point2D(
-            find_zero(area.x_min, corner_values[0], area.x_max, corner_values[1]),
-            area.y_min,
-        ));
-    }
-    if corner_values[0] * corner_values[2] < 0.0 {
-        edge_points.push(Point2D(
-            area.x_min,
-            find_zero(area.y_min, corner_values[0], area.y_max, corner_values[2]),
-        ));
-    }
-    if corner_values[1] * corner_values[3] < 0.0 {
-        edge_points.push(Point2D(
-            area.x_max,
-            find_zero(area.y_min, corner_values[1], area.y_max, corner_values[3]),
-        ));
-    }
-    if corner_values[2] * corner_values[3] < 0.0 {
-        edge_points.push(Point2D(
-            find_zero(area.x_min, corner_values[2], area.x_max, corner_values[3]),
-            area.y_max,
-        ));
+    let corners = [
+        Point2D(area.x_min, area.y_min),
+        Point2D(area.x_max, area.y_min),
+        Point2D(area.x_min, area.y_max),
+        Point2D(area.x_max, area.y_max),
+    ];
+    for (start, end) in [(0, 1), (0, 2), (1, 3), (2, 3)] {
+        if corner_values[start] * corner_values[end] < 0.0 {
+            edge_points.push(find_edge_crossing(
+                f,
+                corners[start],
+                corner_values[start],
+                corners[end],
+                corner_values[end],
+                &EdgeRefinement::default(),
+            ));
+        }
     }
 
+    let vertex = get_leaf_vertex(area, &edge_points, df);
+    (edge_points, vertex)
+}
+
+// Places the cell's vertex where it best fits the tangent lines at the edge
+// points (dual contouring), keeping it inside the cell
+fn get_leaf_vertex(
+    area: &GraphBox,
+    edge_points: &[Point2D],
+    df: &dyn Fn(Point2D) -> Vec2D,
+) -> Point2D {
     if edge_points.len() == 0 {
-        return (
-            edge_points,
-            Point2D(
-                (area.x_min + area.x_max) / 2.0,
-                (area.y_min + area.y_max) / 2.0,
-            ),
+        return Point2D(
+            (area.x_min + area.x_max) / 2.0,
+            (area.y_min + area.y_max) / 2.0,
         );
     }
 
     let mut mean_point = Point2D(0.0, 0.0);
-    for p in &edge_points {
+    for p in edge_points {
         mean_point += *p;
     }
     mean_point.0 /= edge_points.len() as f64;
@@ -718,7 +2886,7 @@ pub fn get_leaf_key_points(
         && point.1 >= area.y_min
         && point.1 <= area.y_max
     {
-        return (edge_points, point);
+        return point;
     }
 
     fn constrain(
@@ -812,8 +2980,519 @@ pub fn get_leaf_key_points(
 
     if valid_solutions.len() > 0 {
         let point = get_best_solution(&valid_solutions, &normals, &edge_points);
-        return (edge_points, point);
+        return point;
+    }
+
+    Point2D(area.x_min, area.y_min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mathjson_value_to_equation, mathjson_value_to_expression};
+
+    fn parse(math_json: &str) -> Equation {
+        mathjson_value_to_equation(&serde_json::from_str(math_json).unwrap()).unwrap()
+    }
+
+    fn graph(math_json: &str, window: GraphBox) -> Vec<Polyline2D> {
+        graph_equation_2d(
+            &"x".to_string(),
+            &"y".to_string(),
+            &window,
+            &parse(math_json),
+            7,
+            3,
+            None,
+            &EdgeRefinement::default(),
+            &HashMap::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn long_sums_are_undefined_instead_of_panicking() {
+        // The whole window needs more than MAX_SERIES_TERMS terms
+        let contours = graph(
+            r#"["Equal","y",["Sum","n",["Tuple","n",1,["Multiply","x","y"]]]]"#,
+            GraphBox::new(400.0, 1000.0, 400.0, 1000.0),
+        );
+        assert!(contours.is_empty());
+    }
+
+    fn level_sets(math_json: &str, levels: &[f64]) -> Vec<LevelSet2D> {
+        let expression =
+            mathjson_value_to_expression(&serde_json::from_str(math_json).unwrap()).unwrap();
+        graph_level_sets_2d(
+            &GraphContext::new("x", "y", &HashMap::new(), &EvaluationBudget::unlimited()),
+            &GraphBox::new(-3.0, 3.0, -3.0, 3.0),
+            &*expression,
+            levels,
+            7,
+            3,
+            &EdgeRefinement::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn level_sets_are_closed_circles() {
+        let level_sets = level_sets(r#"["Add",["Power","x",2],["Power","y",2]]"#, &[1.0, 4.0]);
+        for level_set in level_sets {
+            assert_eq!(level_set.contours.len(), 1);
+            let contour = &level_set.contours[0];
+            assert!(contour.closed);
+            for point in &contour.contour {
+                let radius = (point.0 * point.0 + point.1 * point.1).sqrt();
+                assert!((radius - level_set.level.sqrt()).abs() < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn level_sets_find_levels_the_function_only_touches() {
+        // (y - x)^2 never goes below 0, so no cell has corners on both sides
+        let level_sets = level_sets(r#"["Power",["Subtract","y","x"],2]"#, &[0.0]);
+        assert!(!level_sets[0].contours.is_empty());
+    }
+
+    #[test]
+    fn segments_join_into_open_and_closed_contours() {
+        let p = |x: f64, y: f64| Point2D(x, y);
+        // A path from (0, 0) to (3, 0) given out of order and backwards, and a
+        // separate triangle
+        let segments = [
+            Segment2D(p(2.0, 0.0), p(1.0, 0.0)),
+            Segment2D(p(0.0, 0.0), p(1.0, 0.0)),
+            Segment2D(p(3.0, 0.0), p(2.0, 0.0)),
+            Segment2D(p(5.0, 5.0), p(6.0, 5.0)),
+            Segment2D(p(6.0, 5.0), p(5.0, 6.0)),
+            Segment2D(p(5.0, 6.0), p(5.0, 5.0)),
+        ];
+        // Positive above y = 0, so the path goes left to right
+        let df = |_: Point2D| Vec2D(0.0, 1.0);
+        let contours = segments_to_contours(&segments, &df);
+        assert_eq!(contours.len(), 2);
+
+        let path = contours.iter().find(|contour| !contour.closed).unwrap();
+        let xs: Vec<f64> = path.points.iter().map(|point| point.0).collect();
+        assert_eq!(xs, [0.0, 1.0, 2.0, 3.0]);
+
+        let triangle = contours.iter().find(|contour| contour.closed).unwrap();
+        assert_eq!(triangle.points.len(), 4);
+        assert_eq!(triangle.points.first(), triangle.points.last());
+    }
+
+    #[test]
+    fn implicit_graphs_report_closed_loops() {
+        let contours = graph(
+            r#"["Equal",["Add",["Power","x",2],["Power","y",2]],1]"#,
+            GraphBox::new(-2.0, 2.0, -2.0, 2.0),
+        );
+        assert_eq!(contours.len(), 1);
+        assert!(contours[0].closed);
+        let contours = graph(
+            r#"["Equal",["Add",["Power","x",2],["Power","y",2]],1]"#,
+            GraphBox::new(0.0, 2.0, -2.0, 2.0),
+        );
+        assert!(contours.iter().all(|contour| !contour.closed));
+    }
+
+    fn graph_with_asymptotes(
+        math_json: &str,
+        window: GraphBox,
+    ) -> (Vec<Polyline2D>, Vec<Polyline2D>) {
+        graph_equation_with_asymptotes_2d(
+            &GraphContext::new("x", "y", &HashMap::new(), &EvaluationBudget::unlimited()),
+            &window,
+            &parse(math_json),
+            7,
+            3,
+            None,
+            &EdgeRefinement::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn explicit_functions_break_at_poles() {
+        let window = GraphBox::new(-3.0, 3.0, -3.0, 3.0);
+        let (contours, asymptotes) =
+            graph_with_asymptotes(r#"["Equal","y",["Tan","x"]]"#, window.clone());
+        assert_eq!(contours.len(), 3);
+        for contour in &contours {
+            for w in contour.points.windows(2) {
+                let middle = (w[0].0 + w[1].0) / 2.0;
+                assert!((middle.abs() - std::f64::consts::FRAC_PI_2).abs() > 0.001);
+            }
+        }
+        assert_eq!(asymptotes.len(), 2);
+        for asymptote in &asymptotes {
+            let x = asymptote.points[0].0;
+            assert!((x.abs() - std::f64::consts::FRAC_PI_2).abs() < 1e-6);
+            assert_eq!(asymptote.points[0].0, asymptote.points[1].0);
+        }
+
+        let (contours, asymptotes) =
+            graph_with_asymptotes(r#"["Equal","y",["Divide",1,["Power","x",2]]]"#, window);
+        assert_eq!(contours.len(), 2);
+        assert_eq!(asymptotes.len(), 1);
+        assert!(asymptotes[0].points[0].0.abs() < 1e-6);
+    }
+
+    #[test]
+    fn explicit_functions_stay_joined_where_continuous() {
+        // 100x / sqrt(1 + (100x)^2)
+        const STEEP_SIGMOID: &str = r#"["Equal","y",["Divide",["Multiply",100,"x"],["Sqrt",["Add",1,["Power",["Multiply",100,"x"],2]]]]]"#;
+        let window = GraphBox::new(-3.0, 3.0, -3.0, 3.0);
+        let (contours, asymptotes) = graph_with_asymptotes(STEEP_SIGMOID, window.clone());
+        assert_eq!(contours.len(), 1);
+        assert!(asymptotes.is_empty());
+
+        // Undefined between -1 and 1, but without a pole
+        let (contours, asymptotes) = graph_with_asymptotes(
+            r#"["Equal","y",["Sqrt",["Subtract",["Power","x",2],1]]]"#,
+            window.clone(),
+        );
+        assert_eq!(contours.len(), 2);
+        assert!(asymptotes.is_empty());
+
+        let (contours, asymptotes) =
+            graph_with_asymptotes(r#"["Equal","y",["Divide",["Sin","x"],"x"]]"#, window);
+        assert_eq!(contours.len(), 1);
+        assert!(asymptotes.is_empty());
+    }
+
+    #[test]
+    fn positive_functions_never_touch_zero() {
+        let contours = graph(
+            r#"["Equal",["Add",["Power",["Subtract","y",3.7],2],0.01],0]"#,
+            GraphBox::new(-1000.0, 1000.0, -1000.0, 1000.0),
+        );
+        assert!(contours.is_empty());
+        let contours = graph(
+            r#"["Equal",["Add",["Power",["Subtract","y",37.3],2],1],0]"#,
+            GraphBox::new(-1e5, 1e5, -1e5, 1e5),
+        );
+        assert!(contours.is_empty());
+    }
+
+    #[test]
+    fn double_roots_touch_zero() {
+        let contours = graph(
+            r#"["Equal",["Power",["Subtract","y",["Multiply",0.7,"x"]],2],0]"#,
+            GraphBox::new(-3.1, 2.9, -3.2, 2.8),
+        );
+        assert!(!contours.is_empty());
+        for contour in &contours {
+            for point in &contour.points {
+                assert!((point.1 - 0.7 * point.0).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn gradients_are_only_taken_near_the_curve() {
+        let expression = mathjson_value_to_expression(
+            &serde_json::from_str(r#"["Subtract",["Add",["Power","x",2],["Power","y",2]],1]"#)
+                .unwrap(),
+        )
+        .unwrap();
+        let window = GraphBox::new(-3.0, 3.0, -3.0, 3.0);
+        let var_values = HashMap::new();
+        let evaluate = get_evaluator_2d(&*expression, "x", "y", &var_values);
+        let evaluate_gradient = get_gradient_evaluator_2d(&*expression, "x", "y", &var_values);
+        let samples = SampleCache::for_area(&window);
+        let gradients = SampleCache::<Vec2D>::for_area(&window);
+        let f = |p: Point2D| samples.get_or_evaluate(p, &evaluate);
+        let df = |p: Point2D| gradients.get_or_evaluate(p, &evaluate_gradient);
+        build_tree(7, 3, &window, &f, &df, &EvaluationBudget::unlimited());
+
+        // A circle is nearly flat across the small cells next to it, so only
+        // a few of them need their gradients looked at
+        let values = samples.get_stats().evaluations;
+        let gradients = gradients.get_stats().evaluations;
+        assert!(gradients * 4 < values);
+    }
+
+    #[test]
+    fn roots_and_extrema_of_a_cubic() {
+        // t^3 - 3t has zeros at 0 and ±sqrt(3), a maximum at -1 and a minimum at 1
+        let f = |t: f64| t * t * t - 3.0 * t;
+        let df = |t: f64| 3.0 * t * t - 3.0;
+        let ddf = |t: f64| 6.0 * t;
+        let samples: Vec<f64> = (0..=500).map(|i| -3.0 + 6.0 * i as f64 / 500.0).collect();
+
+        let roots = find_roots_on_samples(&f, Some(&df), &samples, false);
+        let expected = [-3.0_f64.sqrt(), 0.0, 3.0_f64.sqrt()];
+        assert_eq!(roots.len(), expected.len());
+        for ((root, _), expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-9);
+        }
+
+        let extrema = find_roots_on_samples(&df, Some(&ddf), &samples, true);
+        assert_eq!(extrema.len(), 2);
+        assert!((extrema[0].0 + 1.0).abs() < 1e-9 && extrema[0].1 < 0.0);
+        assert!((extrema[1].0 - 1.0).abs() < 1e-9 && extrema[1].1 > 0.0);
+    }
+
+    #[test]
+    fn bracketed_roots_converge_with_or_without_a_derivative() {
+        let g = |t: f64| t * t * t - 2.0;
+        let dg = |t: f64| 3.0 * t * t;
+        let newton = refine_bracketed_root(&g, Some(&dg), 1.0, 2.0);
+        let bisection = refine_bracketed_root(&g, None, 1.0, 2.0);
+        assert!((newton - 2.0_f64.cbrt()).abs() < 1e-9);
+        assert!((bisection - 2.0_f64.cbrt()).abs() < 1e-9);
+    }
+
+    fn points_of_interest(math_json: &str) -> Vec<PointOfInterest2D> {
+        get_equation_points_of_interest_2d(
+            "x",
+            "y",
+            &GraphBox::new(-3.0, 3.0, -3.0, 3.0),
+            &parse(math_json),
+            &HashMap::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn holes_are_points_of_interest() {
+        // (x^2 - 1)/(x - 1) is x + 1 except at x = 1
+        let points = points_of_interest(
+            r#"["Equal","y",["Divide",["Subtract",["Power","x",2],1],["Subtract","x",1]]]"#,
+        );
+        let holes: Vec<_> = points
+            .iter()
+            .filter(|point| point.kind == PointOfInterestKind::Hole)
+            .collect();
+        assert_eq!(holes.len(), 1);
+        assert!((holes[0].point.0 - 1.0).abs() < 1e-6 && (holes[0].point.1 - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn intercepts_are_named_by_axis() {
+        let intercepts = |math_json| -> Vec<_> {
+            points_of_interest(math_json)
+                .into_iter()
+                .filter(|point| {
+                    matches!(
+                        point.kind,
+                        PointOfInterestKind::XIntercept | PointOfInterestKind::YIntercept
+                    )
+                })
+                .map(|point| (point.kind, point.point))
+                .collect()
+        };
+        assert_eq!(
+            intercepts(r#"["Equal","y",["Add","x",1]]"#),
+            [(PointOfInterestKind::YIntercept, Point2D(0.0, 1.0))]
+        );
+        assert_eq!(
+            intercepts(r#"["Equal","x",["Add","y",1]]"#),
+            [(PointOfInterestKind::XIntercept, Point2D(1.0, 0.0))]
+        );
+    }
+
+    fn area(top: &str, bottom: Option<&str>, lower: f64, upper: f64) -> f64 {
+        let parse = |math_json| {
+            mathjson_value_to_expression(&serde_json::from_str(math_json).unwrap()).unwrap()
+        };
+        graph_area_2d(
+            "x".to_string(),
+            parse(top),
+            bottom.map(parse),
+            lower,
+            upper,
+            &HashMap::new(),
+        )
+        .unwrap()
+        .area
+    }
+
+    #[test]
+    fn areas_are_signed() {
+        // -1/2 below the axis on [-1, 0], and 2 above it on [0, 2]
+        assert!((area(r#""x""#, None, -1.0, 2.0) - 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn areas_between_curves() {
+        // x is above x^2 on [0, 1], by 1/2 - 1/3
+        let between = area(r#""x""#, Some(r#"["Power","x",2]"#), 0.0, 1.0);
+        assert!((between - 1.0 / 6.0).abs() < 1e-12);
+    }
+
+    fn leaf() -> QuadTreeNode {
+        QuadTreeNode::Leaf(QuadTreeLeafNode {
+            pieces: vec![],
+            pole_points: vec![],
+        })
+    }
+
+    #[test]
+    fn cells_of_different_sizes_join_into_one_contour() {
+        // The bottom left quadrant is split, so y = 0.3 crosses from two small
+        // leaves into a big one halfway up its left edge
+        let mut tree = QuadTreeNode::Root(Box::new(QuadTreeRootNode {
+            children: [
+                QuadTreeNode::Root(Box::new(QuadTreeRootNode {
+                    children: [leaf(), leaf(), leaf(), leaf()],
+                })),
+                leaf(),
+                leaf(),
+                leaf(),
+            ],
+        }));
+        let window = GraphBox::new(0.0, 1.0, 0.0, 1.0);
+        let f = |Point2D(_, y): Point2D| y - 0.3;
+        let df = |_: Point2D| Vec2D(0.0, 1.0);
+        stitch_tree(&mut tree, &window, &f, &df, &[], &EdgeRefinement::default());
+
+        let contours = get_contours_2d(&tree, &df);
+        assert_eq!(contours.len(), 1);
+        let points = &contours[0].points;
+        assert!(!contours[0].closed);
+        for (i, a) in points.iter().enumerate() {
+            assert!((a.1 - 0.3).abs() < 1e-9);
+            assert!(points[i + 1..].iter().all(|b| a != b));
+        }
+        let mut ends = [points[0].0, points[points.len() - 1].0];
+        ends.sort_by(f64::total_cmp);
+        assert_eq!(ends, [0.0, 1.0]);
+    }
+
+    #[test]
+    fn edge_crossings_reach_the_tolerance() {
+        let evaluations = std::cell::Cell::new(0);
+        let f = |Point2D(x, _): Point2D| {
+            evaluations.set(evaluations.get() + 1);
+            x * x * x - 0.3
+        };
+        let refinement = EdgeRefinement::default();
+        let crossing = find_edge_crossing(
+            &f,
+            Point2D(0.0, 0.0),
+            -0.3,
+            Point2D(1.0, 0.0),
+            0.7,
+            &refinement,
+        );
+
+        assert!((crossing.0 - 0.3_f64.cbrt()).abs() < refinement.tolerance);
+        assert_eq!(crossing.1, 0.0);
+        // The tolerance stops it before it runs out of steps
+        assert!(evaluations.get() < refinement.max_steps);
+    }
+
+    #[test]
+    fn refined_trees_split_where_the_curve_bends() {
+        // A straight line on the left that bends into a parabola on the right
+        let f = |Point2D(x, y): Point2D| y - 2.0 * x.max(0.0).powi(2);
+        let df = |Point2D(x, _): Point2D| Vec2D(-4.0 * x.max(0.0), 1.0);
+        let window = GraphBox::new(-1.0, 1.0, -1.0, 1.0);
+        let budget = EvaluationBudget::unlimited();
+
+        // How many leaves there are on the straight side and the bent side
+        let count_leaves = |tree: &QuadTreeNode| {
+            let mut leaves = vec![];
+            collect_leaves(tree, &window, &mut vec![], &mut leaves);
+            let straight = leaves.iter().filter(|(area, _)| area.x_max <= 0.0).count();
+            (leaves.len(), straight, leaves.len() - straight)
+        };
+
+        let unrefined = build_tree(3, 3, &window, &f, &df, &budget);
+        let (_, straight, bent) = count_leaves(&unrefined);
+        for max_leaves in [30, 60, 200] {
+            let mut tree = unrefined.clone();
+            let adaptive = AdaptiveRefinement::new(max_leaves, 0.0001);
+            refine_tree(&mut tree, &window, 6, &adaptive, &f, &df, &budget);
+
+            // The straight line never needs splitting
+            let (total, refined_straight, refined_bent) = count_leaves(&tree);
+            assert!(total <= max_leaves);
+            assert_eq!(refined_straight, straight);
+            assert!(refined_bent > bent);
+        }
+    }
+
+    fn triangle_area(Triangle3D(a, b, c): &Triangle3D) -> f64 {
+        let (u, v) = ((b.0 - a.0, b.1 - a.1), (c.0 - a.0, c.1 - a.1));
+        (u.0 * v.1 - u.1 * v.0) / 2.0
     }
 
-    (edge_points, Point2D(area.x_min, area.y_min))
+    #[test]
+    fn clipped_triangles_stay_in_the_window() {
+        let window = GraphBox3D::new(-1.0, 1.0, -1.0, 1.0, -1.0, 1.0);
+        let inside = Triangle3D(
+            Point3D(0.0, 0.0, 0.0),
+            Point3D(0.5, 0.0, 0.0),
+            Point3D(0.0, 0.5, 0.0),
+        );
+        assert_eq!(clip_triangle_3d(inside, &window).len(), 1);
+
+        let outside = Triangle3D(
+            Point3D(2.0, 2.0, 0.0),
+            Point3D(3.0, 2.0, 0.0),
+            Point3D(2.0, 3.0, 0.0),
+        );
+        assert!(clip_triangle_3d(outside, &window).is_empty());
+
+        // Only the unit square in the corner of this one is in the window
+        let pieces = clip_triangle_3d(
+            Triangle3D(
+                Point3D(0.0, 0.0, 0.0),
+                Point3D(2.0, 0.0, 0.0),
+                Point3D(0.0, 2.0, 0.0),
+            ),
+            &window,
+        );
+        for piece in &pieces {
+            assert!(triangle_area(piece) > 0.0);
+            let Triangle3D(a, b, c) = piece;
+            for point in [a, b, c] {
+                assert!(point.0 <= 1.0 && point.1 <= 1.0);
+            }
+        }
+        let area: f64 = pieces.iter().map(triangle_area).sum();
+        assert!((area - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn refined_surfaces_have_no_cracks() {
+        // A bump in the middle, so the cells there get split much more
+        let expression = mathjson_value_to_expression(
+            &serde_json::from_str(
+                r#"["Divide",1,["Add",1,["Multiply",50,["Add",["Square","x"],["Square","y"]]]]]"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let triangles = graph_function_3d(
+            &GraphContext::new("x", "y", &HashMap::new(), &EvaluationBudget::unlimited()),
+            &GraphBox3D::new(-1.0, 1.0, -1.0, 1.0, -1.0, 2.0),
+            expression,
+            &SurfaceRefinement::new(2, 7, 4000, 0.002),
+        )
+        .unwrap();
+        assert!(triangles.len() > 2 * 16 * 4);
+
+        // Every edge inside the square is shared with a triangle going the
+        // other way round it
+        let get_key = |point: &Point3D| (point.0.to_bits(), point.1.to_bits());
+        let mut edges = std::collections::HashSet::new();
+        for Triangle3D(a, b, c) in &triangles {
+            for (start, end) in [(a, b), (b, c), (c, a)] {
+                edges.insert((get_key(start), get_key(end)));
+            }
+        }
+        for Triangle3D(a, b, c) in &triangles {
+            for (start, end) in [(a, b), (b, c), (c, a)] {
+                let on_border = [start, end].iter().all(|point| point.0.abs() == 1.0)
+                    || [start, end].iter().all(|point| point.1.abs() == 1.0);
+                assert!(on_border || edges.contains(&(get_key(end), get_key(start))));
+            }
+        }
+    }
 }
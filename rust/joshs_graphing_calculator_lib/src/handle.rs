@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::budget::*;
+use crate::cache::*;
+use crate::equation::*;
+use crate::expression::*;
+use crate::graphing::*;
+use crate::mesh::*;
+use crate::sampling::*;
+use crate::{
+    get_edge_refinement, get_surface_refinement, mathjson_value_to_equation,
+    mathjson_value_to_expression, polylines_to_float_array,
+};
+
+// An equation that stays parsed between calls, so redrawing it after a pan,
+// zoom or slider change doesn't have to parse it, simplify it and take its
+// derivatives all over again
+#[wasm_bindgen]
+pub struct Graph {
+    equation: Equation,
+    // left - right, which gets graphed where it's zero
+    expression: Box<dyn Expression>,
+    var_values: HashMap<String, f64>,
+    // Simplified derivatives of the expression, by variable
+    derivatives: HashMap<String, Box<dyn Expression>>,
+    // Made for the axes of the last 2D graph, and dropped when the variables
+    // change
+    tree_cache: Option<TreeCache>,
+    budget: EvaluationBudget,
+    // What the 3D meshes get coloured by
+    scalar: Option<Box<dyn Expression>>,
+}
+
+#[wasm_bindgen]
+impl Graph {
+    #[wasm_bindgen(constructor)]
+    pub fn new(math_json: String) -> Result<Graph, String> {
+        console_error_panic_hook::set_once();
+
+        let value: Value = serde_json::from_str(&math_json).map_err(|e| e.to_string())?;
+        let equation =
+            mathjson_value_to_equation(&value).ok_or("Could not parse equation".to_string())?;
+        let equation = Equation::new(
+            equation.left.basic_simplify(),
+            equation.right.basic_simplify(),
+            equation.operator,
+        );
+        let expression = Plus::new(vec![
+            equation.left.clone(),
+            Box::new(Minus::new(equation.right.clone())),
+        ])
+        .basic_simplify();
+
+        Ok(Graph {
+            equation,
+            expression,
+            var_values: HashMap::new(),
+            derivatives: HashMap::new(),
+            tree_cache: None,
+            budget: EvaluationBudget::unlimited(),
+            scalar: None,
+        })
+    }
+
+    pub fn set_variable(&mut self, name: String, value: f64) {
+        if self.var_values.get(&name) != Some(&value) {
+            self.var_values.insert(name, value);
+            // The cached trees were made with the old value
+            self.tree_cache = None;
+        }
+    }
+
+    // Replaces all the variable values at once
+    pub fn set_variables(&mut self, var_values: JsValue) -> Result<(), String> {
+        let var_values: HashMap<String, f64> =
+            serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+        if var_values != self.var_values {
+            self.var_values = var_values;
+            self.tree_cache = None;
+        }
+        Ok(())
+    }
+
+    // Limits how much work the graphs can do. The budget is shared rather than
+    // copied, so it can be checked for truncation (or restarted) afterwards, and
+    // it covers every call until it's restarted.
+    pub fn set_budget(&mut self, budget: &EvaluationBudget) {
+        self.budget = budget.clone();
+    }
+
+    // Colours the 3D meshes by this expression, or not at all if it's None
+    pub fn set_scalar(&mut self, scalar_math_json: Option<String>) -> Result<(), String> {
+        self.scalar = match scalar_math_json {
+            Some(scalar_math_json) => {
+                let value: Value =
+                    serde_json::from_str(&scalar_math_json).map_err(|e| e.to_string())?;
+                Some(
+                    mathjson_value_to_expression(&value)
+                        .ok_or("Could not parse scalar".to_string())?,
+                )
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    // Same format as graph_equation_to_float_array, with every list index 0
+    pub fn graph_2d(
+        &mut self,
+        var1: String,
+        var2: String,
+        window: &GraphBox,
+        depth: i64,
+        search_depth: i64,
+        edge_refinement_steps: Option<u32>,
+    ) -> Result<Vec<f64>, String> {
+        let refinement = get_edge_refinement(edge_refinement_steps);
+
+        let contours = if get_explicit_function_2d(&var1, &var2, &self.equation).is_some() {
+            graph_equation_2d(
+                &var1,
+                &var2,
+                window,
+                &self.equation,
+                depth,
+                search_depth,
+                None,
+                &refinement,
+                &self.var_values,
+            )?
+        } else {
+            let same_axes = self
+                .tree_cache
+                .as_ref()
+                .is_some_and(|tree_cache| tree_cache.get_axes() == (&var1, &var2));
+            if !same_axes {
+                let derivatives = (
+                    self.get_derivative(&var1).clone_dyn(),
+                    self.get_derivative(&var2).clone_dyn(),
+                );
+                self.tree_cache = Some(TreeCache::new(
+                    &var1,
+                    &var2,
+                    self.expression.clone_dyn(),
+                    derivatives,
+                ));
+            }
+
+            self.tree_cache.as_mut().unwrap().graph(
+                window,
+                depth,
+                search_depth,
+                &refinement,
+                &self.var_values,
+                &self.budget,
+            )?
+        };
+
+        Ok(polylines_to_float_array(vec![contours]))
+    }
+
+    // How many values the 2D graphs have asked for, and how many of those
+    // actually had to be evaluated, since the variables or axes last changed
+    pub fn get_sampling_stats(&self) -> SamplingStats {
+        self.tree_cache
+            .as_ref()
+            .map_or(SamplingStats::default(), |tree_cache| {
+                tree_cache.get_sampling_stats()
+            })
+    }
+
+    // Same format as graph_equation_to_float_array_3d, which min_depth and
+    // max_depth also default the same as
+    pub fn graph_3d(
+        &self,
+        var1: String,
+        var2: String,
+        var3: String,
+        window: &GraphBox3D,
+        min_depth: Option<u32>,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<f64>, String> {
+        let refinement = get_surface_refinement(min_depth, max_depth);
+        let triangles = graph_equation_3d(
+            &GraphContext::new(&var1, &var2, &self.var_values, &self.budget),
+            &var3,
+            window,
+            &self.equation,
+            &refinement,
+        )?;
+
+        let mut float_array = Vec::with_capacity(triangles.len() * 3 * 3);
+        for triangle in triangles {
+            for point in triangle {
+                float_array.push(point.0);
+                float_array.push(point.1);
+                float_array.push(point.2);
+            }
+        }
+
+        Ok(float_array)
+    }
+
+    // Same as graph_equation_to_mesh_3d, with the scalar from set_scalar
+    pub fn graph_3d_mesh(
+        &self,
+        var1: String,
+        var2: String,
+        var3: String,
+        window: &GraphBox3D,
+        min_depth: Option<u32>,
+        max_depth: Option<u32>,
+    ) -> Result<Mesh3D, String> {
+        let refinement = get_surface_refinement(min_depth, max_depth);
+        graph_equation_mesh_3d(
+            &GraphContext::new(&var1, &var2, &self.var_values, &self.budget),
+            &var3,
+            window,
+            &self.equation,
+            &refinement,
+            self.scalar.as_deref(),
+        )
+    }
+}
+
+impl Graph {
+    fn get_derivative(&mut self, variable: &str) -> &dyn Expression {
+        let expression = &self.expression;
+        &**self
+            .derivatives
+            .entry(variable.to_string())
+            .or_insert_with(|| expression.derivative(variable).basic_simplify())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIRCLE: &str = r#"["Equal",["Add",["Power","x",2],["Power","y",2]],"a"]"#;
+
+    // A contour's sorted points, list index and closed flag
+    type PackedContour = (Vec<(f64, f64)>, f64, f64);
+
+    fn get_contours(float_array: &[f64]) -> Vec<PackedContour> {
+        let mut contours = vec![];
+        let mut points = vec![];
+        let mut i = 0;
+        while i < float_array.len() {
+            if float_array[i] == f64::INFINITY {
+                points.sort_by(|a: &(f64, f64), b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+                contours.push((points, float_array[i + 1], float_array[i + 3]));
+                points = vec![];
+                i += 4;
+            } else {
+                points.push((float_array[i], float_array[i + 1]));
+                i += 2;
+            }
+        }
+        contours.sort_by(|a, b| a.0[0].0.total_cmp(&b.0[0].0));
+        contours
+    }
+
+    #[test]
+    fn unchanged_variables_keep_the_caches() {
+        let mut graph = Graph::new(CIRCLE.to_string()).unwrap();
+        let window = GraphBox::new(-2.0, 2.0, -2.0, 2.0);
+        graph.set_variable("a".to_string(), 1.0);
+        graph
+            .graph_2d("x".to_string(), "y".to_string(), &window, 7, 4, None)
+            .unwrap();
+        let evaluations = graph.get_sampling_stats().evaluations;
+
+        graph.set_variable("a".to_string(), 1.0);
+        assert!(graph.tree_cache.is_some());
+        graph
+            .graph_2d("x".to_string(), "y".to_string(), &window, 7, 4, None)
+            .unwrap();
+        assert_eq!(graph.get_sampling_stats().evaluations, evaluations);
+
+        // A new value drops the trees, but the derivatives don't depend on it
+        graph.set_variable("a".to_string(), 2.0);
+        assert!(graph.tree_cache.is_none());
+        assert_eq!(graph.derivatives.len(), 2);
+    }
+
+    #[test]
+    fn graphs_match_the_uncached_graphs() {
+        let mut graph = Graph::new(CIRCLE.to_string()).unwrap();
+        let window = GraphBox::new(-2.0, 2.0, -2.0, 2.0);
+        graph.set_variable("a".to_string(), 1.0);
+        let cached = graph
+            .graph_2d("x".to_string(), "y".to_string(), &window, 7, 4, None)
+            .unwrap();
+
+        // What graph_equation_to_float_array draws without any lists
+        let equation = mathjson_value_to_equation(&serde_json::from_str(CIRCLE).unwrap()).unwrap();
+        let uncached = polylines_to_float_array(vec![graph_equation_2d(
+            "x",
+            "y",
+            &window,
+            &equation,
+            7,
+            4,
+            None,
+            &get_edge_refinement(None),
+            &graph.var_values,
+        )
+        .unwrap()]);
+
+        let (cached, uncached) = (get_contours(&cached), get_contours(&uncached));
+        assert_eq!(cached.len(), uncached.len());
+        for ((a, a_index, a_closed), (b, b_index, b_closed)) in cached.iter().zip(&uncached) {
+            assert_eq!((a_index, a_closed), (b_index, b_closed));
+            assert_eq!(a.len(), b.len());
+            for (p, q) in a.iter().zip(b) {
+                assert!((p.0 - q.0).abs() < 1e-9 && (p.1 - q.1).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -7,25 +7,41 @@ extern crate console_error_panic_hook;
 extern crate nalgebra as na;
 
 mod ast;
+mod broadcast;
+mod budget;
+mod cache;
 mod equation;
 mod expression;
 mod graphing;
+mod handle;
+mod mesh;
+mod parallel;
 mod point;
+mod raster;
+mod sampling;
 mod segment;
+mod stream;
 mod triangle;
 mod vector;
 
+use broadcast::*;
 use equation::*;
 use expression::*;
 use graphing::*;
+use mesh::*;
+use parallel::*;
 use point::*;
+use raster::*;
 use segment::*;
 use vector::*;
 
 use serde_json::Value;
 
+pub use budget::{CancellationToken, EvaluationBudget};
 pub use graphing::graph_equation_2d;
 pub use graphing::GraphBox;
+pub use handle::Graph;
+pub use stream::GraphStream;
 
 #[wasm_bindgen]
 extern "C" {
@@ -61,41 +77,83 @@ pub fn main() {
     println!("Elapsed: {:.2?}", elapsed);
 }
 
+// Uses the default refinement unless the number of steps is given
+fn get_edge_refinement(steps: Option<u32>) -> EdgeRefinement {
+    match steps {
+        Some(max_steps) => EdgeRefinement {
+            max_steps,
+            ..Default::default()
+        },
+        None => EdgeRefinement::default(),
+    }
+}
+
+// Uses the default refinement, with the depths swapped in where they're given
+fn get_surface_refinement(min_depth: Option<u32>, max_depth: Option<u32>) -> SurfaceRefinement {
+    let default = SurfaceRefinement::default();
+    SurfaceRefinement {
+        min_depth: min_depth.unwrap_or(default.min_depth),
+        max_depth: max_depth.unwrap_or(default.max_depth),
+        ..default
+    }
+}
+
+// Returns one set of contours for each combination of list elements
 pub fn graph_equation(
     math_json: String,
-    var1: &String, // Variable to use as "x" axis
-    var2: &String, // Variable to use as "y" axis
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
     x_min: f64,
     x_max: f64,
     y_min: f64,
     y_max: f64,
     depth: i64,
     search_depth: i64,
-    var_values: JsValue, // HashMap<String, f64>,
-) -> Result<Vec<Contour2D>, String> {
+    var_values: JsValue, // HashMap<String, f64 | Vec<f64>>,
+    edge_refinement_steps: Option<u32>,
+    max_leaves: Option<usize>, // Refine adaptively if given, up to this many leaves
+) -> Result<Vec<Vec<Polyline2D>>, String> {
     console_error_panic_hook::set_once();
 
     let value: Value = serde_json::from_str(&math_json).unwrap();
     let equation = mathjson_value_to_equation(&value);
 
-    let var_values: HashMap<String, f64> = serde_wasm_bindgen::from_value(var_values).unwrap();
+    let var_values: HashMap<String, VariableValue> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
 
     if let Some(equation) = equation {
         let window = GraphBox::new(x_min, x_max, y_min, y_max);
-        return graph_equation_2d(
-            var1,
-            var2,
-            &window,
-            &equation,
-            depth,
-            search_depth,
-            &var_values,
-        );
+        let refinement = get_edge_refinement(edge_refinement_steps);
+        let adaptive = max_leaves.map(|max_leaves| AdaptiveRefinement {
+            max_leaves,
+            ..Default::default()
+        });
+        return broadcast_var_values(&[&*equation.left, &*equation.right], &var_values)?
+            .iter()
+            .map(|var_values| {
+                graph_equation_2d(
+                    var1,
+                    var2,
+                    &window,
+                    &equation,
+                    depth,
+                    search_depth,
+                    adaptive.as_ref(),
+                    &refinement,
+                    var_values,
+                )
+            })
+            .collect();
     }
 
     return Err("Could not parse equation".to_string());
 }
 
+// Each contour is followed by [Infinity, list index, Infinity, closed], where
+// the list index says which combination of list elements the contour was drawn
+// for (or 0 if the equation doesn't use any lists), and closed is 1 if the
+// contour is a loop (ending with its first point) or 0 if not. Readers that
+// split the points on any pair containing Infinity can ignore the closed flag.
 #[wasm_bindgen]
 pub fn graph_equation_to_float_array(
     math_json: String,
@@ -107,11 +165,122 @@ pub fn graph_equation_to_float_array(
     y_max: f64,
     depth: i64,
     search_depth: i64,
-    var_values: JsValue, // HashMap<String, f64>,
+    var_values: JsValue, // HashMap<String, f64 | Vec<f64>>,
+    edge_refinement_steps: Option<u32>,
+    max_leaves: Option<usize>, // Refine adaptively if given, up to this many leaves
+) -> Result<Vec<f64>, String> {
+    console_error_panic_hook::set_once();
+
+    let graphed_equations = graph_equation(
+        math_json,
+        &var1,
+        &var2,
+        x_min,
+        x_max,
+        y_min,
+        y_max,
+        depth,
+        search_depth,
+        var_values,
+        edge_refinement_steps,
+        max_leaves,
+    )?;
+
+    Ok(polylines_to_float_array(graphed_equations))
+}
+
+// Packs one set of polylines for each list index in the format described for
+// graph_equation_to_float_array
+pub(crate) fn polylines_to_float_array(graphs: Vec<Vec<Polyline2D>>) -> Vec<f64> {
+    let total_length = graphs
+        .iter()
+        .flatten()
+        .fold(0, |acc, polyline| acc + 2 * polyline.points.len() + 4);
+
+    let mut float_array = Vec::with_capacity(total_length);
+    for (list_index, polylines) in graphs.into_iter().enumerate() {
+        for polyline in polylines {
+            for point in polyline.points {
+                float_array.push(point.0);
+                float_array.push(point.1);
+            }
+            float_array.extend([f64::INFINITY, list_index as f64, f64::INFINITY]);
+            float_array.push(if polyline.closed { 1.0 } else { 0.0 });
+        }
+    }
+    float_array
+}
+
+// Returns the asymptotes of an implicitly graphed equation, one set for each
+// combination of list elements like graph_equation
+pub fn graph_asymptotes(
+    math_json: String,
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    depth: i64,
+    search_depth: i64,
+    var_values: JsValue, // HashMap<String, f64 | Vec<f64>>,
+    edge_refinement_steps: Option<u32>,
+    max_leaves: Option<usize>, // Refine adaptively if given, up to this many leaves
+) -> Result<Vec<Vec<Polyline2D>>, String> {
+    console_error_panic_hook::set_once();
+
+    let value: Value = serde_json::from_str(&math_json).unwrap();
+    let equation = mathjson_value_to_equation(&value);
+
+    let var_values: HashMap<String, VariableValue> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    if let Some(equation) = equation {
+        let window = GraphBox::new(x_min, x_max, y_min, y_max);
+        let refinement = get_edge_refinement(edge_refinement_steps);
+        let adaptive = max_leaves.map(|max_leaves| AdaptiveRefinement {
+            max_leaves,
+            ..Default::default()
+        });
+        return broadcast_var_values(&[&*equation.left, &*equation.right], &var_values)?
+            .iter()
+            .map(|var_values| {
+                graph_equation_with_asymptotes_2d(
+                    &GraphContext::new(var1, var2, var_values, &EvaluationBudget::unlimited()),
+                    &window,
+                    &equation,
+                    depth,
+                    search_depth,
+                    adaptive.as_ref(),
+                    &refinement,
+                )
+                .map(|(_, asymptotes)| asymptotes)
+            })
+            .collect();
+    }
+
+    Err("Could not parse equation".to_string())
+}
+
+// Same format as graph_equation_to_float_array
+#[wasm_bindgen]
+pub fn graph_asymptotes_to_float_array(
+    math_json: String,
+    var1: String,
+    var2: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    depth: i64,
+    search_depth: i64,
+    var_values: JsValue, // HashMap<String, f64 | Vec<f64>>,
+    edge_refinement_steps: Option<u32>,
+    max_leaves: Option<usize>, // Refine adaptively if given, up to this many leaves
 ) -> Result<Vec<f64>, String> {
     console_error_panic_hook::set_once();
 
-    let graphed_equation = graph_equation(
+    let graphed_asymptotes = graph_asymptotes(
         math_json,
         &var1,
         &var2,
@@ -122,21 +291,230 @@ pub fn graph_equation_to_float_array(
         depth,
         search_depth,
         var_values,
+        edge_refinement_steps,
+        max_leaves,
+    )?;
+
+    Ok(polylines_to_float_array(graphed_asymptotes))
+}
+
+pub fn graph_points_of_interest(
+    math_json: String,
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<PointOfInterest2D>, String> {
+    console_error_panic_hook::set_once();
+
+    let value: Value = serde_json::from_str(&math_json).unwrap();
+    let equation = mathjson_value_to_equation(&value);
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    if let Some(equation) = equation {
+        let window = GraphBox::new(x_min, x_max, y_min, y_max);
+        return get_equation_points_of_interest_2d(var1, var2, &window, &equation, &var_values);
+    }
+
+    Err("Could not parse equation".to_string())
+}
+
+// Returns [x, y, kind] for each point, where kind is a PointOfInterestKind
+#[wasm_bindgen]
+pub fn graph_points_of_interest_to_float_array(
+    math_json: String,
+    var1: String,
+    var2: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<f64>, String> {
+    console_error_panic_hook::set_once();
+
+    let points = graph_points_of_interest(
+        math_json, &var1, &var2, x_min, x_max, y_min, y_max, var_values,
+    )?;
+
+    let mut float_array = Vec::with_capacity(points.len() * 3);
+    for point in points {
+        float_array.push(point.point.0);
+        float_array.push(point.point.1);
+        float_array.push(point.kind as u8 as f64);
+    }
+
+    Ok(float_array)
+}
+
+pub fn graph_area(
+    top_math_json: String,
+    bottom_math_json: Option<String>, // Shades down to the axis if missing
+    var: String,
+    lower: f64,
+    upper: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<ShadedArea2D, String> {
+    console_error_panic_hook::set_once();
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    let top = mathjson_value_to_expression(&serde_json::from_str(&top_math_json).unwrap())
+        .ok_or("Could not parse expression".to_string())?;
+    let bottom = match bottom_math_json {
+        Some(json) => Some(
+            mathjson_value_to_expression(&serde_json::from_str(&json).unwrap())
+                .ok_or("Could not parse expression".to_string())?,
+        ),
+        None => None,
+    };
+
+    graph_area_2d(var, top, bottom, lower, upper, &var_values)
+}
+
+// Returns the signed area, followed by the shaded polygons (separated by infinities)
+#[wasm_bindgen]
+pub fn graph_area_to_float_array(
+    top_math_json: String,
+    bottom_math_json: Option<String>,
+    var: String,
+    lower: f64,
+    upper: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<f64>, String> {
+    console_error_panic_hook::set_once();
+
+    let shaded_area = graph_area(
+        top_math_json,
+        bottom_math_json,
+        var,
+        lower,
+        upper,
+        var_values,
     )?;
 
-    let mut total_length = graphed_equation
+    let total_length = 1 + shaded_area
+        .polygons
         .iter()
-        .fold(0, |acc, contour| acc + 2 * contour.len());
-    total_length += graphed_equation.len() * 2;
+        .fold(0, |acc, polygon| acc + 2 * polygon.len() + 2);
 
     let mut float_array = Vec::with_capacity(total_length);
-    for contour in graphed_equation {
-        for point in contour {
+    float_array.push(shaded_area.area);
+    for polygon in shaded_area.polygons {
+        for point in polygon {
             float_array.push(point.0);
             float_array.push(point.1);
         }
-        float_array.push(std::f64::INFINITY);
-        float_array.push(std::f64::INFINITY);
+        float_array.push(f64::INFINITY);
+        float_array.push(f64::INFINITY);
+    }
+
+    Ok(float_array)
+}
+
+// Draws the given levels, or if there aren't any, level_count evenly spaced
+// levels covering the range of the expression in the window.
+pub fn graph_level_sets(
+    math_json: String,
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    levels: Vec<f64>,
+    level_count: usize,
+    depth: i64,
+    search_depth: i64,
+    var_values: JsValue, // HashMap<String, f64>,
+    edge_refinement_steps: Option<u32>,
+) -> Result<Vec<LevelSet2D>, String> {
+    console_error_panic_hook::set_once();
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    let expression = mathjson_value_to_expression(&serde_json::from_str(&math_json).unwrap())
+        .ok_or("Could not parse expression".to_string())?;
+
+    let window = GraphBox::new(x_min, x_max, y_min, y_max);
+
+    let levels = if levels.is_empty() {
+        let field = sample_scalar_field(var1, var2, &window, &*expression, 64, 64, &var_values)?;
+        if field.min.is_finite() && field.max.is_finite() {
+            get_evenly_spaced_levels(field.min, field.max, level_count)
+        } else {
+            vec![]
+        }
+    } else {
+        levels
+    };
+
+    graph_level_sets_2d(
+        &GraphContext::new(var1, var2, &var_values, &EvaluationBudget::unlimited()),
+        &window,
+        &*expression,
+        &levels,
+        depth,
+        search_depth,
+        &get_edge_refinement(edge_refinement_steps),
+    )
+}
+
+// Returns [level, label x, label y, number of points, x0, y0, x1, y1, ...] for
+// each contour
+#[wasm_bindgen]
+pub fn graph_level_sets_to_float_array(
+    math_json: String,
+    var1: String,
+    var2: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    levels: Vec<f64>,
+    level_count: usize,
+    depth: i64,
+    search_depth: i64,
+    var_values: JsValue, // HashMap<String, f64>,
+    edge_refinement_steps: Option<u32>,
+) -> Result<Vec<f64>, String> {
+    console_error_panic_hook::set_once();
+
+    let level_sets = graph_level_sets(
+        math_json,
+        &var1,
+        &var2,
+        x_min,
+        x_max,
+        y_min,
+        y_max,
+        levels,
+        level_count,
+        depth,
+        search_depth,
+        var_values,
+        edge_refinement_steps,
+    )?;
+
+    let mut float_array = vec![];
+    for level_set in level_sets {
+        for labeled_contour in level_set.contours {
+            float_array.push(level_set.level);
+            float_array.push(labeled_contour.label_anchor.0);
+            float_array.push(labeled_contour.label_anchor.1);
+            float_array.push(labeled_contour.contour.len() as f64);
+            for point in labeled_contour.contour {
+                float_array.push(point.0);
+                float_array.push(point.1);
+            }
+        }
     }
 
     Ok(float_array)
@@ -144,9 +522,9 @@ pub fn graph_equation_to_float_array(
 
 pub fn graph_equation_3d(
     math_json: String,
-    var1: &String, // Variable to use as "x" axis
-    var2: &String, // Variable to use as "y" axis
-    var3: &String, // Variable to use as "z" axis
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
+    var3: &str, // Variable to use as "z" axis
     x_min: f64,
     x_max: f64,
     y_min: f64,
@@ -154,10 +532,12 @@ pub fn graph_equation_3d(
     z_min: f64,
     z_max: f64,
     var_values: JsValue, // HashMap<String, f64>,
+    refinement: &SurfaceRefinement,
 ) -> Result<Vec<Triangle3D>, String> {
     console_error_panic_hook::set_once();
 
-    let var_values: HashMap<String, f64> = serde_wasm_bindgen::from_value(var_values).unwrap();
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
 
     let value: Value = serde_json::from_str(&math_json).unwrap();
 
@@ -165,7 +545,13 @@ pub fn graph_equation_3d(
 
     if let Some(equation) = equation {
         let window = GraphBox3D::new(x_min, x_max, y_min, y_max, z_min, z_max);
-        return graphing::graph_equation_3d(var1, var2, var3, &window, &equation, &var_values);
+        return graphing::graph_equation_3d(
+            &GraphContext::new(var1, var2, &var_values, &EvaluationBudget::unlimited()),
+            var3,
+            &window,
+            &equation,
+            refinement,
+        );
     }
 
     Err("Could not parse equation".to_string())
@@ -184,11 +570,26 @@ pub fn graph_equation_to_float_array_3d(
     z_min: f64,
     z_max: f64,
     var_values: JsValue, // HashMap<String, f64>,
+    min_depth: Option<u32>,
+    max_depth: Option<u32>,
 ) -> Result<Vec<f64>, String> {
     console_error_panic_hook::set_once();
 
+    let refinement = get_surface_refinement(min_depth, max_depth);
+
     let graphed_equation = graph_equation_3d(
-        math_json, &var1, &var2, &var3, x_min, x_max, y_min, y_max, z_min, z_max, var_values,
+        math_json,
+        &var1,
+        &var2,
+        &var3,
+        x_min,
+        x_max,
+        y_min,
+        y_max,
+        z_min,
+        z_max,
+        var_values,
+        &refinement,
     )?;
 
     let total_length = graphed_equation.len() * 3 * 3;
@@ -205,6 +606,167 @@ pub fn graph_equation_to_float_array_3d(
     Ok(float_array)
 }
 
+// Like graph_equation_to_float_array_3d, but as an indexed mesh with smooth
+// normals. If scalar_math_json is given, it's evaluated at each vertex (in
+// terms of var1, var2 and var3) for colouring the surface.
+#[wasm_bindgen]
+pub fn graph_equation_to_mesh_3d(
+    math_json: String,
+    var1: String,
+    var2: String,
+    var3: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    z_min: f64,
+    z_max: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+    min_depth: Option<u32>,
+    max_depth: Option<u32>,
+    scalar_math_json: Option<String>,
+) -> Result<Mesh3D, String> {
+    console_error_panic_hook::set_once();
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+    let value: Value = serde_json::from_str(&math_json).map_err(|e| e.to_string())?;
+    let equation =
+        mathjson_value_to_equation(&value).ok_or("Could not parse equation".to_string())?;
+    let scalar = match scalar_math_json {
+        Some(scalar_math_json) => {
+            let value: Value =
+                serde_json::from_str(&scalar_math_json).map_err(|e| e.to_string())?;
+            Some(mathjson_value_to_expression(&value).ok_or("Could not parse scalar".to_string())?)
+        }
+        None => None,
+    };
+
+    let window = GraphBox3D::new(x_min, x_max, y_min, y_max, z_min, z_max);
+    graph_equation_mesh_3d(
+        &GraphContext::new(&var1, &var2, &var_values, &EvaluationBudget::unlimited()),
+        &var3,
+        &window,
+        &equation,
+        &get_surface_refinement(min_depth, max_depth),
+        scalar.as_deref(),
+    )
+}
+
+// Returns RGBA pixels, row by row from the top of the window
+#[wasm_bindgen]
+pub fn render_domain_coloring_to_rgba(
+    math_json: String,
+    var: String, // Complex variable, like "z"
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    width: usize,
+    height: usize,
+    contour_lines: bool,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<u8>, String> {
+    console_error_panic_hook::set_once();
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    let expression = mathjson_value_to_expression(&serde_json::from_str(&math_json).unwrap())
+        .ok_or("Could not parse expression".to_string())?;
+
+    let window = GraphBox::new(x_min, x_max, y_min, y_max);
+    render_domain_coloring(
+        &var,
+        &*expression,
+        &window,
+        width,
+        height,
+        &var_values,
+        contour_lines,
+    )
+}
+
+pub fn graph_scalar_field(
+    math_json: String,
+    var1: &str, // Variable to use as "x" axis
+    var2: &str, // Variable to use as "y" axis
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    width: usize,
+    height: usize,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<ScalarField2D, String> {
+    console_error_panic_hook::set_once();
+
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+
+    let expression = mathjson_value_to_expression(&serde_json::from_str(&math_json).unwrap())
+        .ok_or("Could not parse expression".to_string())?;
+
+    let window = GraphBox::new(x_min, x_max, y_min, y_max);
+    sample_scalar_field(
+        var1,
+        var2,
+        &window,
+        &*expression,
+        width,
+        height,
+        &var_values,
+    )
+}
+
+// Returns [min, max], followed by the values row by row from the top of the window
+#[wasm_bindgen]
+pub fn graph_scalar_field_to_float_array(
+    math_json: String,
+    var1: String,
+    var2: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    width: usize,
+    height: usize,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<f64>, String> {
+    let field = graph_scalar_field(
+        math_json, &var1, &var2, x_min, x_max, y_min, y_max, width, height, var_values,
+    )?;
+
+    let mut float_array = Vec::with_capacity(field.values.len() + 2);
+    float_array.push(field.min);
+    float_array.push(field.max);
+    float_array.extend(field.values);
+
+    Ok(float_array)
+}
+
+// Returns RGBA pixels, row by row from the top of the window
+#[wasm_bindgen]
+pub fn render_heatmap_to_rgba(
+    math_json: String,
+    var1: String,
+    var2: String,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    width: usize,
+    height: usize,
+    colormap: Colormap,
+    var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<u8>, String> {
+    let field = graph_scalar_field(
+        math_json, &var1, &var2, x_min, x_max, y_min, y_max, width, height, var_values,
+    )?;
+
+    Ok(render_heatmap(&field, colormap))
+}
+
 #[wasm_bindgen]
 pub fn graph_vector_field(
     math_json: JsValue, // Vec<String>,
@@ -216,10 +778,40 @@ pub fn graph_vector_field(
     z_min: f64,
     z_max: f64,
     var_values: JsValue, // HashMap<String, f64>,
+) -> Result<Vec<f64>, String> {
+    graph_vector_field_with_budget(
+        math_json,
+        step,
+        x_min,
+        x_max,
+        y_min,
+        y_max,
+        z_min,
+        z_max,
+        var_values,
+        &EvaluationBudget::unlimited(),
+    )
+}
+
+// Like graph_vector_field, but once the budget runs out the rest of the values
+// are NaN (the points are all still there)
+#[wasm_bindgen]
+pub fn graph_vector_field_with_budget(
+    math_json: JsValue, // Vec<String>,
+    step: f64,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+    z_min: f64,
+    z_max: f64,
+    var_values: JsValue, // HashMap<String, f64>,
+    budget: &EvaluationBudget,
 ) -> Result<Vec<f64>, String> {
     let math_json: Vec<String> = serde_wasm_bindgen::from_value(math_json).unwrap();
 
-    let mut var_values: HashMap<String, f64> = serde_wasm_bindgen::from_value(var_values).unwrap();
+    let var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
 
     let expressions = math_json
         .iter()
@@ -227,6 +819,33 @@ pub fn graph_vector_field(
         .collect::<Option<Vec<Box<dyn Expression>>>>()
         .unwrap();
 
+    sample_vector_field(
+        &expressions,
+        step,
+        &GraphBox3D::new(x_min, x_max, y_min, y_max, z_min, z_max),
+        &var_values,
+        budget,
+    )
+}
+
+// Evaluates the expressions at every point of a grid step apart (lined up with
+// the origin) that covers the window. Each point is x, y and z followed by the
+// expressions' values there.
+pub(crate) fn sample_vector_field(
+    expressions: &[Box<dyn Expression>],
+    step: f64,
+    window: &GraphBox3D,
+    var_values: &HashMap<String, f64>,
+    budget: &EvaluationBudget,
+) -> Result<Vec<f64>, String> {
+    let GraphBox3D {
+        x_min,
+        x_max,
+        y_min,
+        y_max,
+        z_min,
+        z_max,
+    } = *window;
     let x_min = (x_min / step).floor() as i64;
     let x_max = (x_max / step).ceil() as i64;
     let y_min = (y_min / step).floor() as i64;
@@ -234,33 +853,49 @@ pub fn graph_vector_field(
     let z_min = (z_min / step).floor() as i64;
     let z_max = (z_max / step).ceil() as i64;
 
-    let capacity: usize = (x_max - x_min + 1) as usize
-        * (y_max - y_min + 1) as usize
-        * (z_max - z_min + 1) as usize
-        * (3 + expressions.len());
-
-    let mut result = vec![0.0; capacity];
-
-    let mut i = 0;
-    for x in x_min..=x_max {
-        var_values.insert("x".to_string(), (x as f64) * step);
+    let slab_capacity: usize =
+        (y_max - y_min + 1) as usize * (z_max - z_min + 1) as usize * (3 + expressions.len());
 
+    // Each x value gets its own slab of the result, where each expression is
+    // evaluated in one batch, on its own thread with the parallel feature on
+    let slabs: Vec<Result<Vec<f64>, String>> = map_range((x_max - x_min + 1) as usize, |i| {
+        let x = (x_min + i as i64) as f64 * step;
+        let mut points = vec![];
         for y in y_min..=y_max {
-            var_values.insert("y".to_string(), (y as f64) * step);
-
             for z in z_min..=z_max {
-                var_values.insert("z".to_string(), (z as f64) * step);
-
-                result[i + 0] = (x as f64) * step;
-                result[i + 1] = (y as f64) * step;
-                result[i + 2] = (z as f64) * step;
-                i += 3;
-                for expression in &expressions {
-                    result[i] = expression.evaluate(&var_values)?;
-                    i += 1;
-                }
+                points.push(((y as f64) * step, (z as f64) * step));
+            }
+        }
+        let xs = vec![x; points.len()];
+        let ys: Vec<f64> = points.iter().map(|point| point.0).collect();
+        let zs: Vec<f64> = points.iter().map(|point| point.1).collect();
+        let values = if budget.is_exhausted() {
+            vec![vec![f64::NAN; points.len()]; expressions.len()]
+        } else {
+            budget.spend(points.len() * expressions.len());
+            let batch = BatchValues::new(var_values, vec![("x", &xs), ("y", &ys), ("z", &zs)]);
+            expressions
+                .iter()
+                .map(|expression| expression.evaluate_batch(&batch))
+                .collect::<Result<Vec<_>, String>>()?
+        };
+
+        let mut slab = Vec::with_capacity(slab_capacity);
+        for (k, (y, z)) in points.into_iter().enumerate() {
+            slab.push(x);
+            slab.push(y);
+            slab.push(z);
+            for expression_values in &values {
+                slab.push(expression_values[k]);
             }
         }
+
+        Ok(slab)
+    });
+
+    let mut result = Vec::with_capacity(slabs.len() * slab_capacity);
+    for slab in slabs {
+        result.extend(slab?);
     }
 
     Ok(result)
@@ -282,7 +917,8 @@ pub fn graph_vector_field_paths(
 ) -> Result<Vec<f64>, String> {
     let math_json: Vec<String> = serde_wasm_bindgen::from_value(math_json).unwrap();
 
-    let mut var_values: HashMap<String, f64> = serde_wasm_bindgen::from_value(var_values).unwrap();
+    let mut var_values: HashMap<String, f64> =
+        serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
 
     let expressions = math_json
         .iter()
@@ -366,11 +1002,30 @@ fn mathjson_value_to_expression(value: &Value) -> Option<Box<dyn Expression>> {
         Value::String(s) => match s.as_str() {
             "Pi" => Some(Box::new(Constant::new(std::f64::consts::PI))),
             "ExponentialE" => Some(Box::new(Constant::new(std::f64::consts::E))),
+            "ImaginaryUnit" => Some(Box::new(ImaginaryUnit::new())),
             "Nothing" => None,
             _ => Some(Box::new(Variable::new(s.to_string()))),
         },
         Value::Array(a) => {
             let operator = a[0].as_str()?;
+
+            // Operators with a bound variable have to be handled before converting
+            // the operands, because their limits aren't expressions.
+            if operator == "Integrate" {
+                let integrand = mathjson_value_to_expression(a.get(1)?)?;
+                let (variable, lower, upper) = mathjson_value_to_limits(a.get(2)?)?;
+                return Some(Box::new(Integral::new(integrand, variable, lower, upper)));
+            }
+            if operator == "Sum" || operator == "Product" {
+                let body = mathjson_value_to_expression(a.get(1)?)?;
+                let (variable, lower, upper) = mathjson_value_to_limits(a.get(2)?)?;
+                return Some(if operator == "Sum" {
+                    Box::new(Sum::new(body, variable, lower, upper))
+                } else {
+                    Box::new(Product::new(body, variable, lower, upper))
+                });
+            }
+
             let mut operands: Vec<Box<dyn Expression>> = Vec::new();
             for operand in a[1..].iter() {
                 operands.push(mathjson_value_to_expression(operand)?);
@@ -401,6 +1056,16 @@ fn mathjson_value_to_expression(value: &Value) -> Option<Box<dyn Expression>> {
                 "Cos" => Some(Box::new(Cos::new(operands[0].clone()))),
                 "Tan" => Some(Box::new(Tan::new(operands[0].clone()))),
                 "Abs" => Some(Box::new(Abs::new(operands[0].clone()))),
+                "Real" => Some(Box::new(Re::new(operands[0].clone()))),
+                "Imaginary" => Some(Box::new(Im::new(operands[0].clone()))),
+                "Argument" => Some(Box::new(Arg::new(operands[0].clone()))),
+                "Complex" => Some(Box::new(Plus::new(vec![
+                    operands[0].clone(),
+                    Box::new(Times::new(vec![
+                        operands[1].clone(),
+                        Box::new(ImaginaryUnit::new()),
+                    ])),
+                ]))),
                 "Square" => Some(Box::new(Power::new(
                     operands[0].clone(),
                     Box::new(Constant::new(2.0)),
@@ -410,6 +1075,7 @@ fn mathjson_value_to_expression(value: &Value) -> Option<Box<dyn Expression>> {
                     Box::new(Constant::new(0.5)),
                 ))),
                 "Delimiter" => Some(operands[0].clone()),
+                "List" => Some(Box::new(List::new(operands))),
                 "Rational" => Some(Box::new(Times::new(vec![
                     operands[0].clone(),
                     Box::new(Inverse::new(operands[1].clone())),
@@ -420,3 +1086,27 @@ fn mathjson_value_to_expression(value: &Value) -> Option<Box<dyn Expression>> {
         _ => None,
     }
 }
+
+// A bound variable with its lower and upper bounds
+type Limits = (String, Box<dyn Expression>, Box<dyn Expression>);
+
+// Parses limits like ["Tuple", "x", 0, 1] (or ["Triple", ["Hold", "x"], 0, 1])
+// into the bound variable and its lower and upper bounds.
+fn mathjson_value_to_limits(value: &Value) -> Option<Limits> {
+    let a = value.as_array()?;
+    if a.len() != 4 || !matches!(a[0].as_str()?, "Tuple" | "Triple" | "Limits") {
+        return None;
+    }
+
+    let variable = match &a[1] {
+        Value::String(s) => s.to_string(),
+        Value::Array(hold) if hold.len() == 2 && hold[0].as_str()? == "Hold" => {
+            hold[1].as_str()?.to_string()
+        }
+        _ => return None,
+    };
+
+    let lower = mathjson_value_to_expression(&a[2])?;
+    let upper = mathjson_value_to_expression(&a[3])?;
+    Some((variable, lower, upper))
+}
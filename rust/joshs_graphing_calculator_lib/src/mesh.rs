@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::equation::*;
+use crate::expression::*;
+use crate::graphing::*;
+use crate::point::*;
+use crate::triangle::Triangle3D;
+
+// A 3D graph with each vertex stored once, ready to go in a vertex buffer and
+// an index buffer, and a normal at each vertex so it can be shaded smoothly
+#[wasm_bindgen]
+pub struct Mesh3D {
+    // x, y and z of each vertex
+    positions: Vec<f64>,
+    // Unit normals, laid out like the positions
+    normals: Vec<f64>,
+    // Three vertices for each triangle, anticlockwise when seen from the side
+    // the normals point out of
+    indices: Vec<u32>,
+    // A value for each vertex to colour it by, or nothing if none was asked for
+    scalars: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl Mesh3D {
+    pub fn get_positions(&self) -> Vec<f64> {
+        self.positions.clone()
+    }
+
+    pub fn get_normals(&self) -> Vec<f64> {
+        self.normals.clone()
+    }
+
+    pub fn get_indices(&self) -> Vec<u32> {
+        self.indices.clone()
+    }
+
+    pub fn get_scalars(&self) -> Vec<f64> {
+        self.scalars.clone()
+    }
+
+    pub fn get_vertex_count(&self) -> usize {
+        self.positions.len() / 3
+    }
+}
+
+impl Mesh3D {
+    // Merges the corners that triangles share, which is exact since
+    // neighbouring triangles work their shared corners out the same way
+    pub fn from_triangles(triangles: Vec<Triangle3D>) -> Self {
+        let mut positions = vec![];
+        let mut indices = Vec::with_capacity(triangles.len() * 3);
+        let mut vertices = HashMap::new();
+        for triangle in triangles {
+            for point in triangle {
+                let key = (
+                    (point.0 + 0.0).to_bits(),
+                    (point.1 + 0.0).to_bits(),
+                    (point.2 + 0.0).to_bits(),
+                );
+                let index = *vertices.entry(key).or_insert_with(|| {
+                    positions.extend([point.0, point.1, point.2]);
+                    (positions.len() / 3 - 1) as u32
+                });
+                indices.push(index);
+            }
+        }
+
+        Mesh3D {
+            normals: vec![0.0; positions.len()],
+            positions,
+            indices,
+            scalars: vec![],
+        }
+    }
+
+    fn get_position(&self, vertex: usize) -> Point3D {
+        Point3D(
+            self.positions[3 * vertex],
+            self.positions[3 * vertex + 1],
+            self.positions[3 * vertex + 2],
+        )
+    }
+
+    // Where the gradient couldn't be worked out (like on the rim of a
+    // hemisphere, where it's infinite), averages the normals of the triangles
+    // around the vertex instead, weighted by their areas
+    fn fill_in_normals(&mut self, missing: &[bool]) {
+        let mut sums = vec![[0.0; 3]; missing.len()];
+        for triangle in self.indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| self.get_position(triangle[k] as usize));
+            let (u, v) = (
+                [b.0 - a.0, b.1 - a.1, b.2 - a.2],
+                [c.0 - a.0, c.1 - a.1, c.2 - a.2],
+            );
+            let cross = [
+                u[1] * v[2] - u[2] * v[1],
+                u[2] * v[0] - u[0] * v[2],
+                u[0] * v[1] - u[1] * v[0],
+            ];
+            for &vertex in triangle {
+                for i in 0..3 {
+                    sums[vertex as usize][i] += cross[i];
+                }
+            }
+        }
+
+        for (vertex, sum) in sums.into_iter().enumerate() {
+            if !missing[vertex] {
+                continue;
+            }
+            let length = (sum[0] * sum[0] + sum[1] * sum[1] + sum[2] * sum[2]).sqrt();
+            let normal = if length > 0.0 && length.is_finite() {
+                sum.map(|component| component / length)
+            } else {
+                [0.0, 0.0, 1.0]
+            };
+            self.normals[3 * vertex..3 * vertex + 3].copy_from_slice(&normal);
+        }
+    }
+}
+
+// Graphs the equation like graph_equation_3d, as a mesh. The normals come
+// from the gradient of the function the equation is solved for, and if a
+// scalar expression is given (in terms of var1, var2 and var3), its value at
+// each vertex goes in the scalars.
+pub fn graph_equation_mesh_3d(
+    context: &GraphContext,
+    var3: &str,
+    window: &GraphBox3D,
+    equation: &Equation,
+    refinement: &SurfaceRefinement,
+    scalar: Option<&dyn Expression>,
+) -> Result<Mesh3D, String> {
+    let GraphContext {
+        var_values, budget, ..
+    } = *context;
+    let (input1, input2, output, expression) =
+        get_explicit_function_3d(context.var1, context.var2, var3, equation).ok_or(
+            "Cannot plot 3D equation that is not solved in terms of one variable".to_string(),
+        )?;
+    let derivatives = (
+        expression.derivative(&input1).basic_simplify(),
+        expression.derivative(&input2).basic_simplify(),
+    );
+
+    let triangles = graph_function_3d(
+        &context.with_axes(&input1, &input2),
+        window,
+        expression,
+        refinement,
+    )?;
+    let mut mesh = Mesh3D::from_triangles(triangles);
+
+    // The surface is z = f(x, y) as far as the mesh is concerned, which has
+    // (-df/dx, -df/dy, 1) as a normal
+    let vertex_count = mesh.get_vertex_count();
+    let coordinates: Vec<Vec<f64>> = (0..3)
+        .map(|i| {
+            (0..vertex_count)
+                .map(|vertex| mesh.positions[3 * vertex + i])
+                .collect()
+        })
+        .collect();
+    let batch = BatchValues::new(
+        var_values,
+        vec![(&input1, &coordinates[0]), (&input2, &coordinates[1])],
+    );
+    let dx = derivatives.0.evaluate_batch(&batch)?;
+    let dy = derivatives.1.evaluate_batch(&batch)?;
+    budget.spend(2 * vertex_count);
+
+    let mut missing = vec![false; vertex_count];
+    for vertex in 0..vertex_count {
+        let length = (dx[vertex] * dx[vertex] + dy[vertex] * dy[vertex] + 1.0).sqrt();
+        if length.is_finite() {
+            let normal = [-dx[vertex] / length, -dy[vertex] / length, 1.0 / length];
+            mesh.normals[3 * vertex..3 * vertex + 3].copy_from_slice(&normal);
+        } else {
+            missing[vertex] = true;
+        }
+    }
+    if missing.contains(&true) {
+        mesh.fill_in_normals(&missing);
+    }
+
+    if let Some(scalar) = scalar {
+        mesh.scalars = scalar.evaluate_batch(&BatchValues::new(
+            var_values,
+            vec![
+                (&input1, &coordinates[0]),
+                (&input2, &coordinates[1]),
+                (&output, &coordinates[2]),
+            ],
+        ))?;
+        budget.spend(vertex_count);
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::*;
+    use crate::mathjson_value_to_equation;
+
+    #[test]
+    fn paraboloid_normals_come_from_the_gradient() {
+        let equation = mathjson_value_to_equation(
+            &serde_json::from_str(r#"["Equal","z",["Add",["Power","x",2],["Power","y",2]]]"#)
+                .unwrap(),
+        )
+        .unwrap();
+        let mesh = graph_equation_mesh_3d(
+            &GraphContext::new("x", "y", &HashMap::new(), &EvaluationBudget::unlimited()),
+            "z",
+            &GraphBox3D::new(-1.0, 1.0, -1.0, 1.0, -1.0, 3.0),
+            &equation,
+            &SurfaceRefinement::default(),
+            None,
+        )
+        .unwrap();
+
+        // Every corner is shared by more than one triangle
+        let triangle_count = mesh.indices.len() / 3;
+        assert!(triangle_count > 0);
+        assert!(mesh.get_vertex_count() < 3 * triangle_count);
+
+        for vertex in 0..mesh.get_vertex_count() {
+            let Point3D(x, y, _) = mesh.get_position(vertex);
+            let length = (4.0 * x * x + 4.0 * y * y + 1.0).sqrt();
+            let expected = [-2.0 * x / length, -2.0 * y / length, 1.0 / length];
+            let normal = &mesh.normals[3 * vertex..3 * vertex + 3];
+            for (component, expected) in normal.iter().zip(expected) {
+                assert!((component - expected).abs() < 1e-9);
+            }
+        }
+    }
+}
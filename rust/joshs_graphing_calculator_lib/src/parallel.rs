@@ -0,0 +1,115 @@
+// Helpers that spread work over a thread pool when the parallel feature is on,
+// and just do it in order otherwise. Either way the results come back in the
+// same order, so the output doesn't depend on the feature.
+
+// Runs both closures, possibly at the same time
+pub(crate) fn join<A: Send, B: Send>(
+    a: impl FnOnce() -> A + Send,
+    b: impl FnOnce() -> B + Send,
+) -> (A, B) {
+    #[cfg(feature = "parallel")]
+    {
+        rayon::join(a, b)
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        (a(), b())
+    }
+}
+
+// Returns [f(0), f(1), ..., f(n - 1)], possibly working them out at the same time
+pub(crate) fn map_range<T: Send>(n: usize, f: impl Fn(usize) -> T + Send + Sync) -> Vec<T> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        (0..n).into_par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        (0..n).map(f).collect()
+    }
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::budget::*;
+    use crate::graphing::*;
+    use crate::point::*;
+    use crate::{mathjson_value_to_equation, mathjson_value_to_expression, sample_vector_field};
+
+    // Runs f on a pool with one thread, where rayon does everything in order
+    fn in_order<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(f)
+    }
+
+    #[test]
+    fn trees_are_the_same_in_parallel() {
+        let expression = mathjson_value_to_expression(
+            &serde_json::from_str(r#"["Subtract",["Add",["Power","x",2],["Power","y",2]],1]"#)
+                .unwrap(),
+        )
+        .unwrap();
+        let var_values = HashMap::new();
+        let f = get_evaluator_2d(&*expression, "x", "y", &var_values);
+        let df = get_gradient_evaluator_2d(&*expression, "x", "y", &var_values);
+        let window = GraphBox::new(-2.0, 2.0, -2.0, 2.0);
+        let build = || {
+            let tree = build_tree(7, 3, &window, &f, &df, &EvaluationBudget::unlimited());
+            serde_json::to_string(&tree).unwrap()
+        };
+
+        assert_eq!(build(), in_order(build));
+    }
+
+    #[test]
+    fn surfaces_are_the_same_in_parallel() {
+        let equation = mathjson_value_to_equation(
+            &serde_json::from_str(r#"["Equal","z",["Sin",["Multiply","x","y"]]]"#).unwrap(),
+        )
+        .unwrap();
+        let var_values = HashMap::new();
+        let graph = || -> Vec<Point3D> {
+            graph_equation_3d(
+                &GraphContext::new("x", "y", &var_values, &EvaluationBudget::unlimited()),
+                "z",
+                &GraphBox3D::new(-3.0, 3.0, -3.0, 3.0, -1.0, 1.0),
+                &equation,
+                &SurfaceRefinement::default(),
+            )
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect()
+        };
+
+        let points = graph();
+        assert!(!points.is_empty());
+        assert_eq!(points, in_order(graph));
+    }
+
+    #[test]
+    fn vector_fields_are_the_same_in_parallel() {
+        let expressions = [r#"["Negate","y"]"#, r#""x""#, r#"["Multiply","x","z"]"#].map(|json| {
+            mathjson_value_to_expression(&serde_json::from_str(json).unwrap()).unwrap()
+        });
+        let var_values = HashMap::new();
+        let sample = || {
+            sample_vector_field(
+                &expressions,
+                0.25,
+                &GraphBox3D::new(-2.0, 2.0, -2.0, 2.0, -1.0, 1.0),
+                &var_values,
+                &EvaluationBudget::unlimited(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(sample(), in_order(sample));
+    }
+}
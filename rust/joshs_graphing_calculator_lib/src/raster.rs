@@ -0,0 +1,311 @@
+use num::complex::Complex64;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use wasm_bindgen::prelude::*;
+
+use crate::expression::*;
+use crate::graphing::*;
+use crate::point::*;
+
+// Pixel buffers are RGBA, one byte per channel, row by row from the top of the
+// window (y_max) down. Each pixel is sampled at its center.
+fn pixel_center(window: &GraphBox, width: usize, height: usize, i: usize, j: usize) -> (f64, f64) {
+    (
+        window.x_min + (window.x_max - window.x_min) * (i as f64 + 0.5) / width as f64,
+        window.y_max - (window.y_max - window.y_min) * (j as f64 + 0.5) / height as f64,
+    )
+}
+
+// h, s, and l are all between 0 and 1
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> [u8; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let h = h.rem_euclid(1.0) * 6.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    [
+        ((r + m) * 255.0).round() as u8,
+        ((g + m) * 255.0).round() as u8,
+        ((b + m) * 255.0).round() as u8,
+    ]
+}
+
+// Colors each pixel z by f(z), with the hue showing arg(f(z)) and the brightness
+// showing |f(z)| (so zeros are black and poles are white). With contour lines,
+// pixels are darkened where |f| crosses a power of 2 or arg(f) crosses a
+// multiple of 30 degrees. Pixels where f is undefined are transparent.
+pub fn render_domain_coloring(
+    var: &str,
+    expression: &dyn Expression,
+    window: &GraphBox,
+    width: usize,
+    height: usize,
+    var_values: &HashMap<String, f64>,
+    contour_lines: bool,
+) -> Result<Vec<u8>, String> {
+    const ARG_CONTOURS: f64 = 12.0;
+
+    let mut variables = to_complex_values(var_values);
+    variables.insert(var.to_string(), Complex64::new(0.0, 0.0));
+
+    let mut values = Vec::with_capacity(width * height);
+    for j in 0..height {
+        for i in 0..width {
+            let (x, y) = pixel_center(window, width, height, i, j);
+            *variables.get_mut(var).unwrap() = Complex64::new(x, y);
+            values.push(expression.evaluate_complex(&variables)?);
+        }
+    }
+
+    // Which band of each contour family a value falls in
+    let bands = |value: Complex64| {
+        (
+            value.norm().log2().floor(),
+            (value.arg() / (2.0 * PI) * ARG_CONTOURS).floor(),
+        )
+    };
+
+    let mut pixels = vec![0; width * height * 4];
+    for j in 0..height {
+        for i in 0..width {
+            let value = values[j * width + i];
+            if !value.re.is_finite() || !value.im.is_finite() {
+                continue;
+            }
+
+            let lightness = 2.0 / PI * value.norm().atan();
+            let [mut r, mut g, mut b] = hsl_to_rgb(value.arg() / (2.0 * PI), 1.0, lightness);
+
+            if contour_lines {
+                let band = bands(value);
+                let on_line = [(i + 1, j), (i, j + 1)]
+                    .iter()
+                    .filter(|(i, j)| *i < width && *j < height)
+                    .any(|(i, j)| {
+                        let neighbor = bands(values[j * width + i]);
+                        neighbor.0 != band.0 || neighbor.1 != band.1
+                    });
+                if on_line {
+                    r /= 2;
+                    g /= 2;
+                    b /= 2;
+                }
+            }
+
+            let index = (j * width + i) * 4;
+            pixels[index..index + 4].copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+
+    Ok(pixels)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScalarField2D {
+    pub width: usize,
+    pub height: usize,
+    // Row by row from the top of the window, like the pixel buffers
+    pub values: Vec<f64>,
+    // Range of the finite values (NaN if there aren't any)
+    pub min: f64,
+    pub max: f64,
+}
+
+pub fn sample_scalar_field(
+    var1: &str,
+    var2: &str,
+    window: &GraphBox,
+    expression: &dyn Expression,
+    width: usize,
+    height: usize,
+    var_values: &HashMap<String, f64>,
+) -> Result<ScalarField2D, String> {
+    check_variables_2d(expression, var1, var2, var_values)?;
+    let f = get_evaluator_2d(expression, var1, var2, var_values);
+
+    let mut values = Vec::with_capacity(width * height);
+    let mut min = f64::NAN;
+    let mut max = f64::NAN;
+    for j in 0..height {
+        for i in 0..width {
+            let (x, y) = pixel_center(window, width, height, i, j);
+            let value = f(Point2D(x, y));
+            if value.is_finite() {
+                min = min.min(value);
+                max = max.max(value);
+            }
+            values.push(value);
+        }
+    }
+
+    Ok(ScalarField2D {
+        width,
+        height,
+        values,
+        min,
+        max,
+    })
+}
+
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Colormap {
+    Viridis = 0,
+    Grayscale = 1,
+    // Blue for negative values and red for positive values, centered on 0
+    Diverging = 2,
+}
+
+impl Colormap {
+    fn stops(&self) -> &'static [[u8; 3]] {
+        match self {
+            Colormap::Viridis => &[
+                [68, 1, 84],
+                [71, 44, 122],
+                [59, 81, 139],
+                [44, 113, 142],
+                [33, 144, 141],
+                [39, 173, 129],
+                [92, 200, 99],
+                [170, 220, 50],
+                [253, 231, 37],
+            ],
+            Colormap::Grayscale => &[[0, 0, 0], [255, 255, 255]],
+            Colormap::Diverging => &[[59, 76, 192], [221, 221, 221], [180, 4, 38]],
+        }
+    }
+
+    // t is between 0 and 1
+    pub fn color(&self, t: f64) -> [u8; 3] {
+        let stops = self.stops();
+        let position = t.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let index = (position.floor() as usize).min(stops.len() - 2);
+        let s = position - index as f64;
+
+        let mut color = [0; 3];
+        for channel in 0..3 {
+            let a = stops[index][channel] as f64;
+            let b = stops[index + 1][channel] as f64;
+            color[channel] = (a + (b - a) * s).round() as u8;
+        }
+        color
+    }
+}
+
+// Colors a scalar field from its min (or -max |value| for diverging colormaps)
+// to its max. Non-finite values are transparent.
+pub fn render_heatmap(field: &ScalarField2D, colormap: Colormap) -> Vec<u8> {
+    let (low, high) = match colormap {
+        Colormap::Diverging => {
+            let extent = field.min.abs().max(field.max.abs());
+            (-extent, extent)
+        }
+        _ => (field.min, field.max),
+    };
+
+    let mut pixels = vec![0; field.values.len() * 4];
+    for (index, value) in field.values.iter().enumerate() {
+        if !value.is_finite() {
+            continue;
+        }
+
+        let t = if high > low {
+            (value - low) / (high - low)
+        } else {
+            0.5
+        };
+        let [r, g, b] = colormap.color(t);
+        pixels[index * 4..index * 4 + 4].copy_from_slice(&[r, g, b, 255]);
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mathjson_value_to_expression;
+
+    fn parse(math_json: &str) -> Box<dyn Expression> {
+        mathjson_value_to_expression(&serde_json::from_str(math_json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hues_go_round_the_primaries() {
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(1.0 / 3.0, 1.0, 0.5), [0, 255, 0]);
+        assert_eq!(hsl_to_rgb(2.0 / 3.0, 1.0, 0.5), [0, 0, 255]);
+        assert_eq!(hsl_to_rgb(1.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(0.3, 1.0, 0.0), [0, 0, 0]);
+        assert_eq!(hsl_to_rgb(0.3, 1.0, 1.0), [255, 255, 255]);
+    }
+
+    // The middle pixel of a 3 by 3 image of [-1, 1] x [-1, 1] is at 0
+    fn middle_pixel(math_json: &str) -> [u8; 4] {
+        let pixels = render_domain_coloring(
+            "z",
+            &*parse(math_json),
+            &GraphBox::new(-1.0, 1.0, -1.0, 1.0),
+            3,
+            3,
+            &HashMap::new(),
+            false,
+        )
+        .unwrap();
+        pixels[16..20].try_into().unwrap()
+    }
+
+    #[test]
+    fn zeros_are_black() {
+        assert_eq!(middle_pixel(r#""z""#), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn undefined_pixels_are_transparent() {
+        assert_eq!(middle_pixel(r#"["Divide",1,"z"]"#), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ranges_skip_values_that_are_not_finite() {
+        // The middle column is at x = 0, where 1/x is infinite
+        let field = sample_scalar_field(
+            "x",
+            "y",
+            &GraphBox::new(-1.0, 1.0, -1.0, 1.0),
+            &*parse(r#"["Divide",1,"x"]"#),
+            3,
+            3,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert!(field.values[1].is_infinite());
+        assert!((field.min + 1.5).abs() < 1e-12 && (field.max - 1.5).abs() < 1e-12);
+
+        let pixels = render_heatmap(&field, Colormap::Viridis);
+        assert_eq!(pixels[4..8], [0, 0, 0, 0]);
+        assert_eq!(pixels[0..3], Colormap::Viridis.color(0.0));
+    }
+
+    #[test]
+    fn diverging_heatmaps_are_centered_on_zero() {
+        let field = ScalarField2D {
+            width: 3,
+            height: 1,
+            values: vec![-1.0, 0.0, 4.0],
+            min: -1.0,
+            max: 4.0,
+        };
+        let pixels = render_heatmap(&field, Colormap::Diverging);
+        assert_eq!(pixels[4..7], Colormap::Diverging.color(0.5));
+        assert_eq!(pixels[0..3], Colormap::Diverging.color(0.375));
+        assert_eq!(pixels[8..11], Colormap::Diverging.color(1.0));
+    }
+}
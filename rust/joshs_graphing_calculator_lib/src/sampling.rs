@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use wasm_bindgen::prelude::*;
+
+use crate::graphing::*;
+use crate::point::*;
+
+// Points are snapped to a grid this many halvings finer than the area they're
+// in, which is much finer than any tree gets
+pub(crate) const QUANTIZATION_BITS: i32 = 40;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SamplingStats {
+    // How many times a value was asked for
+    pub lookups: usize,
+    // How many of those actually had to be evaluated
+    pub evaluations: usize,
+}
+
+// Remembers a function's values at the points it's been sampled at, so the
+// corners shared by neighbouring cells (and looked at again when the leaves get
+// stitched) only get evaluated once. Points are keyed by where they are on a
+// fine grid rather than by their exact coordinates, so a corner still matches
+// when it's been worked out from a different cell with different rounding.
+pub struct SampleCache<T = f64> {
+    origin: Point2D,
+    quantum: f64,
+    values: Mutex<HashMap<(i64, i64), T>>,
+    lookups: AtomicUsize,
+    evaluations: AtomicUsize,
+}
+
+impl<T: Copy> SampleCache<T> {
+    // Points are snapped to multiples of the quantum away from the origin
+    pub fn new(origin: Point2D, quantum: f64) -> Self {
+        SampleCache {
+            origin,
+            quantum,
+            values: Mutex::new(HashMap::new()),
+            lookups: AtomicUsize::new(0),
+            evaluations: AtomicUsize::new(0),
+        }
+    }
+
+    // Starts over with a different grid, but keeps counting
+    pub fn reset(&mut self, origin: Point2D, quantum: f64) {
+        self.origin = origin;
+        self.quantum = quantum;
+        self.clear();
+    }
+
+    pub fn for_area(area: &GraphBox) -> Self {
+        let size = (area.x_max - area.x_min).max(area.y_max - area.y_min);
+        SampleCache::new(
+            Point2D(area.x_min, area.y_min),
+            size * 2.0_f64.powi(-QUANTIZATION_BITS),
+        )
+    }
+
+    // Points too far from the origin to fit in the grid don't get a key, and
+    // are always evaluated
+    fn get_key(&self, Point2D(x, y): Point2D) -> Option<(i64, i64)> {
+        const LIMIT: f64 = (1_i64 << 62) as f64;
+        let x = ((x - self.origin.0) / self.quantum).round();
+        let y = ((y - self.origin.1) / self.quantum).round();
+        if x.abs() < LIMIT && y.abs() < LIMIT {
+            Some((x as i64, y as i64))
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, point: Point2D) -> bool {
+        match self.get_key(point) {
+            Some(key) => self.values.lock().unwrap().contains_key(&key),
+            None => false,
+        }
+    }
+
+    pub fn get_or_evaluate(&self, point: Point2D, f: &impl Fn(Point2D) -> T) -> T {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let key = self.get_key(point);
+        if let Some(key) = key {
+            if let Some(value) = self.values.lock().unwrap().get(&key) {
+                return *value;
+            }
+        }
+
+        // Evaluate without holding the lock, so other threads can keep going
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        let value = f(point);
+        if let Some(key) = key {
+            self.values.lock().unwrap().insert(key, value);
+        }
+        value
+    }
+
+    // Adds values that were worked out some other way (like in a batch)
+    pub fn insert(&self, samples: impl IntoIterator<Item = (Point2D, T)>) {
+        let mut values = self.values.lock().unwrap();
+        for (point, value) in samples {
+            self.evaluations.fetch_add(1, Ordering::Relaxed);
+            if let Some(key) = self.get_key(point) {
+                values.insert(key, value);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+
+    // Forgets the values, but keeps counting
+    pub fn clear(&self) {
+        self.values.lock().unwrap().clear();
+    }
+
+    pub fn get_stats(&self) -> SamplingStats {
+        SamplingStats {
+            lookups: self.lookups.load(Ordering::Relaxed),
+            evaluations: self.evaluations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn corners_with_different_rounding_share_a_value() {
+        let samples = SampleCache::for_area(&GraphBox::new(-1.0, 1.0, -1.0, 1.0));
+        let calls = Cell::new(0);
+        let f = |Point2D(x, y): Point2D| {
+            calls.set(calls.get() + 1);
+            x + y
+        };
+
+        // 0.1 + 0.2 != 0.3, but they're the same point on the grid
+        let first = samples.get_or_evaluate(Point2D(0.1 + 0.2, -0.5), &f);
+        let second = samples.get_or_evaluate(Point2D(0.3, -0.5), &f);
+        assert_eq!(first, second);
+        assert_eq!(calls.get(), 1);
+
+        // A step of the tree's size is a different point
+        samples.get_or_evaluate(Point2D(0.3 + 1.0 / 1024.0, -0.5), &f);
+        assert_eq!(calls.get(), 2);
+        assert_eq!(samples.len(), 2);
+
+        let stats = samples.get_stats();
+        assert_eq!((stats.lookups, stats.evaluations), (3, 2));
+    }
+
+    #[test]
+    fn points_off_the_grid_are_always_evaluated() {
+        let samples = SampleCache::for_area(&GraphBox::new(0.0, 1.0, 0.0, 1.0));
+        let calls = Cell::new(0);
+        let f = |_: Point2D| {
+            calls.set(calls.get() + 1);
+            1.0
+        };
+
+        let far = Point2D(1e300, 0.0);
+        samples.get_or_evaluate(far, &f);
+        samples.get_or_evaluate(far, &f);
+        assert_eq!(calls.get(), 2);
+        assert!(!samples.contains(far));
+        assert_eq!(samples.len(), 0);
+    }
+
+    #[test]
+    fn inserted_values_are_looked_up_on_the_grid() {
+        let mut samples = SampleCache::new(Point2D(0.0, 0.0), 0.5f64.powi(20));
+        samples.insert([(Point2D(0.25, 0.75), 2.0)]);
+        assert!(samples.contains(Point2D(0.25 + 1e-9, 0.75 - 1e-9)));
+        assert_eq!(
+            samples.get_or_evaluate(Point2D(0.25, 0.75), &|_| unreachable!()),
+            2.0
+        );
+
+        // Resetting forgets the values, but keeps counting
+        samples.reset(Point2D(0.0, 0.0), 1.0);
+        assert!(!samples.contains(Point2D(0.25, 0.75)));
+        assert_eq!(samples.get_stats().evaluations, 1);
+    }
+}
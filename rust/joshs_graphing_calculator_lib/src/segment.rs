@@ -12,4 +12,11 @@ pub struct Segment2D(pub Point2D, pub Point2D);
 
 pub type Contour2D = Vec<Point2D>;
 
+// A contour joined from segments. Closed loops end with their first point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Polyline2D {
+    pub points: Contour2D,
+    pub closed: bool,
+}
+
 // pub type Contour3D = Vec<Point3D>;
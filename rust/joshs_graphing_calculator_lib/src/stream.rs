@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::budget::*;
+use crate::equation::*;
+use crate::expression::*;
+use crate::graphing::*;
+use crate::point::*;
+use crate::sampling::*;
+use crate::vector::*;
+use crate::{get_edge_refinement, mathjson_value_to_equation, polylines_to_float_array};
+
+// Graphs an equation a bit at a time, so a worker can post something to draw
+// long before the whole graph is done. The first chunk is the graph at the
+// search depth, and each chunk after that is the whole graph again one level
+// deeper, which replaces the one before. Chunks are in the same format as
+// graph_equation_to_float_array (with every list index 0).
+#[wasm_bindgen]
+pub struct GraphStream {
+    var1: String,
+    var2: String,
+    window: GraphBox,
+    equation: Equation,
+    // left - right, which gets graphed where it's zero
+    expression: Box<dyn Expression>,
+    derivatives: (Box<dyn Expression>, Box<dyn Expression>),
+    var_values: HashMap<String, f64>,
+    depth: i64,
+    search_depth: i64,
+    refinement: EdgeRefinement,
+    // Unstitched, so the leaves can still be split
+    tree: Option<QuadTreeNode>,
+    // The leaves left to split to get to the next level
+    pending: Vec<(GraphBox, Vec<u64>)>,
+    // How deep the last chunk went
+    finished_depth: i64,
+    done: bool,
+    samples: SampleCache,
+    gradients: SampleCache<Vec2D>,
+    budget: EvaluationBudget,
+}
+
+#[wasm_bindgen]
+impl GraphStream {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        math_json: String,
+        var1: String,
+        var2: String,
+        x_min: f64,
+        x_max: f64,
+        y_min: f64,
+        y_max: f64,
+        depth: i64,
+        search_depth: i64,
+        var_values: JsValue, // HashMap<String, f64>
+        edge_refinement_steps: Option<u32>,
+    ) -> Result<GraphStream, String> {
+        console_error_panic_hook::set_once();
+
+        let value: Value = serde_json::from_str(&math_json).map_err(|e| e.to_string())?;
+        let equation =
+            mathjson_value_to_equation(&value).ok_or("Could not parse equation".to_string())?;
+        let var_values: HashMap<String, f64> =
+            serde_wasm_bindgen::from_value(var_values).map_err(|e| e.to_string())?;
+        let window = GraphBox::new(x_min, x_max, y_min, y_max);
+        let refinement = get_edge_refinement(edge_refinement_steps);
+
+        Ok(GraphStream {
+            refinement,
+            ..GraphStream::from_equation(
+                equation,
+                var1,
+                var2,
+                window,
+                depth,
+                search_depth,
+                var_values,
+            )
+        })
+    }
+
+    // Works on the graph for about time_budget milliseconds, and returns the
+    // next chunk if one got finished, or undefined if not (an empty array is a
+    // finished chunk with nothing to draw). The first call
+    // always returns a chunk, however long it takes. Stitching the leaves
+    // together at the end of a level isn't split up, so it can go over budget.
+    pub fn next_chunk(&mut self, time_budget: f64) -> Result<Option<Vec<f64>>, String> {
+        let deadline = now() + time_budget;
+        if self.done {
+            return Ok(None);
+        }
+
+        // Explicit functions are quick enough to do in one go
+        if get_explicit_function_2d(&self.var1, &self.var2, &self.equation).is_some() {
+            self.done = true;
+            let contours = graph_equation_2d(
+                &self.var1,
+                &self.var2,
+                &self.window,
+                &self.equation,
+                self.depth,
+                self.search_depth,
+                None,
+                &self.refinement,
+                &self.var_values,
+            )?;
+            return Ok(Some(polylines_to_float_array(vec![contours])));
+        }
+
+        check_variables_2d(&*self.expression, &self.var1, &self.var2, &self.var_values)?;
+
+        let GraphStream {
+            var1,
+            var2,
+            window,
+            expression,
+            derivatives,
+            var_values,
+            depth,
+            search_depth,
+            refinement,
+            tree,
+            pending,
+            finished_depth,
+            done,
+            samples,
+            gradients,
+            budget,
+            ..
+        } = self;
+        let evaluate = get_evaluator_2d(&**expression, var1, var2, var_values);
+        let evaluate = |p: Point2D| {
+            budget.spend(1);
+            evaluate(p)
+        };
+        let f = |p: Point2D| samples.get_or_evaluate(p, &evaluate);
+        let evaluate_gradient = get_gradient_evaluator_from_derivatives_2d(
+            derivatives.0.clone(),
+            derivatives.1.clone(),
+            var1,
+            var2,
+            var_values,
+        );
+        let evaluate_gradient = get_cheating_gradient(&evaluate_gradient);
+        let df = |p: Point2D| gradients.get_or_evaluate(p, &evaluate_gradient);
+
+        let exclusions = expression.get_real_domain().get_exclusions();
+        let exclusions: Vec<_> = exclusions
+            .iter()
+            .map(|exclusion| get_evaluator_2d(&**exclusion, var1, var2, var_values))
+            .collect();
+        let exclusions: Vec<&dyn Fn(Point2D) -> f64> = exclusions
+            .iter()
+            .map(|exclusion| exclusion as &dyn Fn(Point2D) -> f64)
+            .collect();
+        let stitch =
+            |tree: &QuadTreeNode| stitch_copy(tree, window, &f, &df, &exclusions, refinement);
+
+        let tree = match tree {
+            Some(tree) => tree,
+            None => {
+                sample_tree_corners_2d(
+                    &**expression,
+                    &GraphContext::new(var1, var2, var_values, budget),
+                    window,
+                    *search_depth,
+                    samples,
+                )?;
+                let tree = tree.insert(build_tree(
+                    *search_depth,
+                    *search_depth,
+                    window,
+                    &f,
+                    &df,
+                    budget,
+                ));
+                *finished_depth = *search_depth;
+                *done = *finished_depth >= *depth;
+                return Ok(Some(stitch(tree)));
+            }
+        };
+
+        if pending.is_empty() {
+            collect_leaves(tree, window, &mut vec![], pending);
+            // Nothing left to split, so the last chunk was already as good as it gets
+            if pending.is_empty() {
+                *done = true;
+                return Ok(None);
+            }
+        }
+        while let Some((area, path)) = pending.pop() {
+            // Once the budget runs out, the level so far is as far as it goes
+            if budget.is_exhausted() {
+                *done = true;
+                return Ok(Some(stitch(tree)));
+            }
+            *node_at(tree, &path) = build_tree(1, 1, &area, &f, &df, budget);
+            if pending.is_empty() {
+                *finished_depth += 1;
+                *done = *finished_depth >= *depth;
+                return Ok(Some(stitch(tree)));
+            }
+            if now() >= deadline {
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Limits how much work the whole stream can do, shared like
+    // Graph::set_budget. If it runs out partway through a level, that level is
+    // the last chunk.
+    pub fn set_budget(&mut self, budget: &EvaluationBudget) {
+        self.budget = budget.clone();
+    }
+
+    // Whether the last chunk was the whole graph at the full depth, or as
+    // close as the budget allowed
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // How deep the last chunk went
+    pub fn get_depth(&self) -> i64 {
+        self.finished_depth
+    }
+}
+
+impl GraphStream {
+    // Uses the default edge refinement
+    pub fn from_equation(
+        equation: Equation,
+        var1: String,
+        var2: String,
+        window: GraphBox,
+        depth: i64,
+        search_depth: i64,
+        var_values: HashMap<String, f64>,
+    ) -> Self {
+        let equation = Equation::new(
+            equation.left.basic_simplify(),
+            equation.right.basic_simplify(),
+            equation.operator,
+        );
+        let expression = Plus::new(vec![
+            equation.left.clone(),
+            Box::new(Minus::new(equation.right.clone())),
+        ])
+        .basic_simplify();
+        let derivatives = (
+            expression.derivative(&var1).basic_simplify(),
+            expression.derivative(&var2).basic_simplify(),
+        );
+        GraphStream {
+            var1,
+            var2,
+            samples: SampleCache::for_area(&window),
+            gradients: SampleCache::for_area(&window),
+            window,
+            equation,
+            expression,
+            derivatives,
+            var_values,
+            depth,
+            search_depth: search_depth.min(depth),
+            refinement: EdgeRefinement::default(),
+            tree: None,
+            pending: vec![],
+            finished_depth: 0,
+            done: false,
+            budget: EvaluationBudget::unlimited(),
+        }
+    }
+}
+
+// Stitches a copy of the tree, since the original's leaves might still need
+// splitting, and returns its contours
+fn stitch_copy(
+    tree: &QuadTreeNode,
+    window: &GraphBox,
+    f: &impl Fn(Point2D) -> f64,
+    df: &impl Fn(Point2D) -> Vec2D,
+    exclusions: &[&dyn Fn(Point2D) -> f64],
+    refinement: &EdgeRefinement,
+) -> Vec<f64> {
+    let mut tree = tree.clone();
+    stitch_tree(&mut tree, window, f, df, exclusions, refinement);
+    polylines_to_float_array(vec![get_contours_2d(&tree, df)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_get_deeper_until_done() {
+        let value: Value =
+            serde_json::from_str(r#"["Equal", ["Add", ["Square", "x"], ["Square", "y"]], 1]"#)
+                .unwrap();
+        let mut stream = GraphStream::from_equation(
+            mathjson_value_to_equation(&value).unwrap(),
+            "x".to_string(),
+            "y".to_string(),
+            GraphBox::new(-2.0, 2.0, -2.0, 2.0),
+            6,
+            3,
+            HashMap::new(),
+        );
+
+        // The first call always finishes a chunk
+        let first = stream.next_chunk(0.0).unwrap();
+        assert!(first.is_some_and(|chunk| !chunk.is_empty()));
+        assert_eq!(stream.get_depth(), 3);
+
+        let mut last = None;
+        while !stream.is_done() {
+            if let Some(chunk) = stream.next_chunk(1000.0).unwrap() {
+                last = Some(chunk);
+            }
+        }
+        assert_eq!(stream.get_depth(), 6);
+        assert!(last.is_some_and(|chunk| !chunk.is_empty()));
+        assert_eq!(stream.next_chunk(1000.0).unwrap(), None);
+    }
+}